@@ -0,0 +1,373 @@
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
+
+use anyhow::{bail, Context};
+use flate2::read::MultiGzDecoder;
+use zip::ZipArchive;
+
+use crate::{
+    config,
+    extract::{detect, FileType, Kind, SAMPLE_LEN},
+    mail,
+};
+
+/// Separates the path of an archive from the path of a member inside it, as
+/// in `backup.zip!/docs/a.md`.
+pub const SEPARATOR: &str = "!/";
+
+/// Returns the path of the file on disk holding `path`, which may be the
+/// virtual path of an archive member.
+pub fn real_path(path: &str) -> &str {
+    path.split_once(SEPARATOR).map_or(path, |(real, _)| real)
+}
+
+/// What is left to unpack from an archive, shared by the archives nested in
+/// it.
+struct Budget {
+    /// Decompressed bytes allowed in all.
+    max_bytes: u64,
+    /// Decompressed bytes still allowed.
+    bytes: u64,
+    max_depth: usize,
+    max_member_size: u64,
+}
+
+/// Calls `f` with the virtual path, type and content of every member of the
+/// archive at `path` that has text to extract, descending into nested
+/// archives. Fails if the archive unpacks to more than
+/// `indexing.max_archive_size` bytes, so that a zip bomb cannot exhaust memory.
+/// Mailboxes are unpacked even when `indexing.max_archive_depth` is 0.
+pub fn for_each_member(
+    path: &str,
+    kind: Kind,
+    bytes: &[u8],
+    f: &mut dyn FnMut(String, FileType, Vec<u8>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let indexing = &config::get().indexing;
+    let mut budget = Budget {
+        max_bytes: indexing.max_archive_size(),
+        bytes: indexing.max_archive_size(),
+        max_depth: indexing.max_archive_depth(),
+        max_member_size: indexing.max_file_size(),
+    };
+    if budget.max_depth == 0 && kind != Kind::Mbox {
+        return Ok(());
+    }
+    unpack(path, kind, bytes, 1, &mut budget, f)
+}
+
+fn unpack(
+    path: &str,
+    kind: Kind,
+    bytes: &[u8],
+    depth: usize,
+    budget: &mut Budget,
+    f: &mut dyn FnMut(String, FileType, Vec<u8>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    match kind {
+        Kind::Zip => {
+            let mut archive = ZipArchive::new(Cursor::new(bytes))
+                .with_context(|| format!("invalid zip archive {path}"))?;
+            for i in 0..archive.len() {
+                let entry = archive
+                    .by_index(i)
+                    .with_context(|| format!("unable to read zip archive {path}"))?;
+                // Skips directories, and names escaping the archive
+                let Some(name) = entry.enclosed_name().filter(|_| entry.is_file()) else {
+                    continue;
+                };
+                let name = name.to_string_lossy().into_owned();
+                if let Some(content) = read_member(path, entry, budget)? {
+                    member(
+                        &format!("{path}{SEPARATOR}{name}"),
+                        content,
+                        depth,
+                        budget,
+                        f,
+                    )?;
+                }
+            }
+        }
+        Kind::Tar => {
+            let mut archive = tar::Archive::new(Cursor::new(bytes));
+            let entries = archive
+                .entries()
+                .with_context(|| format!("invalid tar archive {path}"))?;
+            for entry in entries {
+                let entry = entry.with_context(|| format!("unable to read tar archive {path}"))?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = entry
+                    .path()?
+                    .to_string_lossy()
+                    .trim_start_matches("./")
+                    .to_string();
+                if let Some(content) = read_member(path, entry, budget)? {
+                    member(
+                        &format!("{path}{SEPARATOR}{name}"),
+                        content,
+                        depth,
+                        budget,
+                        f,
+                    )?;
+                }
+            }
+        }
+        Kind::Gzip => {
+            let decoder = MultiGzDecoder::new(bytes);
+            let Some(content) = read_member(path, decoder, budget)? else {
+                return Ok(());
+            };
+            // A compressed tar archive is unpacked as one archive, so that
+            // its members are `a.tar.gz!/x` rather than `a.tar.gz!/a.tar!/x`
+            let name = path.rsplit(['/', '!']).next().unwrap_or(path);
+            let name = match name.strip_suffix(".tgz") {
+                Some(stem) => format!("{stem}.tar"),
+                None => name.trim_end_matches(".gz").to_string(),
+            };
+            let sample = &content[..content.len().min(SAMPLE_LEN as usize)];
+            if detect(&name, sample).kind == Kind::Tar {
+                return unpack(path, Kind::Tar, &content, depth, budget, f);
+            }
+            member(
+                &format!("{path}{SEPARATOR}{name}"),
+                content,
+                depth,
+                budget,
+                f,
+            )?;
+        }
+        Kind::Mbox => {
+            // Messages are named after their Message-ID, so that they keep
+            // their path when messages before them are deleted
+            let mut names = HashSet::new();
+            for (i, message) in mail::mbox_messages(bytes).enumerate() {
+                let message = message.with_context(|| format!("unable to read mailbox {path}"))?;
+                charge(path, message.len() as u64, budget)?;
+                let name = mail::message_id(&message)
+                    .filter(|id| !names.contains(id))
+                    .unwrap_or_else(|| (i + 1).to_string());
+                names.insert(name.clone());
+                let file_type = FileType {
+                    kind: Kind::Email,
+                    mime: "message/rfc822",
+                };
+                f(format!("{path}{SEPARATOR}{name}"), file_type, message)?;
+            }
+        }
+        _ => bail!("{path} is not an archive"),
+    }
+    Ok(())
+}
+
+/// Reads a member of the archive at `path`, charging it to the budget.
+/// Returns `None` for members too large to index on their own.
+fn read_member(
+    path: &str,
+    reader: impl Read,
+    budget: &mut Budget,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let limit = budget.max_member_size.min(budget.bytes);
+    let mut content = vec![];
+    reader
+        .take(limit + 1)
+        .read_to_end(&mut content)
+        .with_context(|| format!("unable to unpack {path}"))?;
+    let size = content.len() as u64;
+    charge(path, size, budget)?;
+    Ok((size <= budget.max_member_size).then_some(content))
+}
+
+/// Takes `size` decompressed bytes from the budget of the archive at `path`.
+fn charge(path: &str, size: u64, budget: &mut Budget) -> anyhow::Result<()> {
+    if size > budget.bytes {
+        bail!(
+            "{path} unpacks to more than {} bytes, see indexing.max_archive_size",
+            budget.max_bytes
+        );
+    }
+    budget.bytes -= size;
+    Ok(())
+}
+
+/// Hands the member at `path` to `f`, or unpacks it if it is itself an
+/// archive.
+fn member(
+    path: &str,
+    content: Vec<u8>,
+    depth: usize,
+    budget: &mut Budget,
+    f: &mut dyn FnMut(String, FileType, Vec<u8>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let file_type = detect(path, &content[..content.len().min(SAMPLE_LEN as usize)]);
+    match file_type.kind {
+        Kind::Unsupported => Ok(()),
+        kind if kind.is_archive() => {
+            if depth >= budget.max_depth {
+                eprintln!("Not unpacking {path}: archives nested too deeply");
+                return Ok(());
+            }
+            unpack(path, kind, &content, depth + 1, budget, f)
+        }
+        _ => f(path.to_string(), file_type, content),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        for (name, content) in files {
+            if let Some(dir) = name.strip_suffix('/') {
+                writer
+                    .add_directory(dir, SimpleFileOptions::default())
+                    .unwrap();
+            } else {
+                writer
+                    .start_file(*name, SimpleFileOptions::default())
+                    .unwrap();
+                writer.write_all(content).unwrap();
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn tar_gz(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::fast()));
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *content).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn budget(max_bytes: u64, max_depth: usize, max_member_size: u64) -> Budget {
+        Budget {
+            max_bytes,
+            bytes: max_bytes,
+            max_depth,
+            max_member_size,
+        }
+    }
+
+    /// Unpacks `bytes`, returning the paths of the members found.
+    fn members(path: &str, kind: Kind, bytes: &[u8], budget: &mut Budget) -> Vec<String> {
+        let mut paths = vec![];
+        unpack(path, kind, bytes, 1, budget, &mut |path, _, _| {
+            paths.push(path);
+            Ok(())
+        })
+        .unwrap();
+        paths
+    }
+
+    #[test]
+    fn unpacks_nested_archives() {
+        let inner = zip(&[("b.txt", b"bee")]);
+        let outer = zip(&[
+            ("docs/", b""),
+            ("docs/a.txt", b"hello"),
+            ("inner.zip", &inner),
+            ("image.png", b"\x89PNG\r\n\x1a\n"),
+        ]);
+        assert_eq!(
+            members("a.zip", Kind::Zip, &outer, &mut budget(1 << 20, 3, 1 << 20)),
+            ["a.zip!/docs/a.txt", "a.zip!/inner.zip!/b.txt"]
+        );
+        let tgz = tar_gz(&[("./x.md", b"# x"), ("inner.zip", &inner)]);
+        assert_eq!(
+            members("a.tgz", Kind::Gzip, &tgz, &mut budget(1 << 20, 3, 1 << 20)),
+            ["a.tgz!/x.md", "a.tgz!/inner.zip!/b.txt"]
+        );
+    }
+
+    #[test]
+    fn names_messages_after_their_message_id() {
+        let message = |id: &str| {
+            format!(
+                "From a@example.com Mon Jan  1 10:00:00 2024\nFrom: a@example.com\nDate: Mon, 1 Jan 2024 10:00:00 +0000\n{id}\nHi\n\n"
+            )
+        };
+        let mbox = [
+            message("Message-ID: <1@example.com>\n"),
+            message(""),
+            message("Message-ID: <1@example.com>\n"),
+        ]
+        .concat();
+        assert_eq!(
+            members(
+                "Inbox",
+                Kind::Mbox,
+                mbox.as_bytes(),
+                &mut budget(1 << 20, 0, 0)
+            ),
+            ["Inbox!/1@example.com", "Inbox!/2", "Inbox!/3"]
+        );
+    }
+
+    #[test]
+    fn stops_at_the_maximum_depth() {
+        let inner = zip(&[("b.txt", b"bee")]);
+        let outer = zip(&[("a.txt", b"hello"), ("inner.zip", &inner)]);
+        assert_eq!(
+            members("a.zip", Kind::Zip, &outer, &mut budget(1 << 20, 1, 1 << 20)),
+            ["a.zip!/a.txt"]
+        );
+    }
+
+    #[test]
+    fn skips_names_escaping_the_archive() {
+        let archive = zip(&[
+            ("../evil.txt", b"evil"),
+            ("/abs.txt", b"abs"),
+            ("ok.txt", b"ok"),
+        ]);
+        let paths = members(
+            "a.zip",
+            Kind::Zip,
+            &archive,
+            &mut budget(1 << 20, 3, 1 << 20),
+        );
+        assert_eq!(paths, ["a.zip!/ok.txt"]);
+    }
+
+    #[test]
+    fn skips_large_members_but_charges_them() {
+        let archive = zip(&[("big.txt", &[b'a'; 100]), ("small.txt", b"small")]);
+        let mut budget = budget(1 << 20, 3, 10);
+        assert_eq!(
+            members("a.zip", Kind::Zip, &archive, &mut budget),
+            ["a.zip!/small.txt"]
+        );
+        assert_eq!(budget.bytes, (1 << 20) - 11 - 5);
+    }
+
+    #[test]
+    fn fails_beyond_the_size_budget() {
+        let archive = zip(&[("a.txt", &[b'a'; 60]), ("b.txt", &[b'b'; 60])]);
+        let mut budget = budget(100, 3, 1 << 20);
+        let error = unpack(
+            "a.zip",
+            Kind::Zip,
+            &archive,
+            1,
+            &mut budget,
+            &mut |_, _, _| Ok(()),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "a.zip unpacks to more than 100 bytes, see indexing.max_archive_size"
+        );
+    }
+}
@@ -0,0 +1,1079 @@
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+/// Free-form information about where a chunk comes from, e.g. the headings
+/// above it. Stored as JSON alongside the chunk.
+pub type Metadata = serde_json::Map<String, Value>;
+
+/// A span of a document, as it appears in the source text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub text: String,
+    /// Byte offsets of the chunk in the source text, end exclusive.
+    pub byte_start: usize,
+    pub byte_end: usize,
+    /// 1-based line numbers of the first and last line of the chunk.
+    pub line_start: usize,
+    pub line_end: usize,
+    /// Number of tokens in the chunk.
+    pub token_count: usize,
+    pub metadata: Metadata,
+}
+
+/// How a document is split into sections before chunking.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Prose, chunked by sentences and paragraphs.
+    Text,
+    /// Sections under each heading, tagged with the heading path.
+    Markdown,
+    /// Sections at each function, class or module, tagged with the symbol
+    /// name, chunked by lines.
+    Code,
+    /// One row per line, the first being row `first_row` of its table.
+    /// Chunked by rows, tagged with the range of rows. If `header` is set,
+    /// the first row names the columns and is repeated at the start of every
+    /// chunk.
+    Table { first_row: usize, header: bool },
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match ext.as_str() {
+            "md" | "markdown" | "mdown" | "mkd" => Format::Markdown,
+            "rs" | "js" | "jsx" | "mjs" | "ts" | "tsx" | "py" | "java" | "c" | "cpp" | "cc"
+            | "h" | "hpp" | "go" | "rb" | "php" | "pl" | "pm" | "lua" | "r" | "scala" | "kt"
+            | "swift" | "dart" | "hs" | "ml" | "fs" | "fsx" | "cs" | "vb" | "elm" | "clj"
+            | "cljs" | "ex" | "exs" | "nim" | "cr" | "zig" | "jl" | "coffee" | "sh" | "bash"
+            | "zsh" | "fish" | "sql" => Format::Code,
+            _ => Format::Text,
+        }
+    }
+}
+
+/// A part of a document with a format and metadata of its own, e.g. a page
+/// of a PDF. No chunk spans two regions.
+#[derive(Debug, Clone)]
+pub struct Region {
+    /// Byte offsets of the region in the document's text, end exclusive.
+    pub start: usize,
+    pub end: usize,
+    pub format: Format,
+    pub metadata: Metadata,
+}
+
+impl Region {
+    /// A region covering all of `text`.
+    pub fn whole(text: &str, format: Format) -> Self {
+        Self {
+            start: 0,
+            end: text.len(),
+            format,
+            metadata: Metadata::new(),
+        }
+    }
+}
+
+/// A part of a region chunked on its own.
+struct Section {
+    start: usize,
+    end: usize,
+    metadata: Metadata,
+}
+
+/// A sentence or line, or a piece of one if it is too long for a chunk.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    start: usize,
+    end: usize,
+    tokens: usize,
+    /// The segment is the last one of its paragraph.
+    paragraph_end: bool,
+}
+
+/// Splits the `regions` of `text` into chunks of about `max_tokens` tokens,
+/// as counted by `count_tokens`.
+///
+/// Each region is first split into sections according to its format, and no
+/// chunk spans two sections. Within a section, chunks are made of whole
+/// sentences (or lines, for code) where possible, and preferably end at the
+/// end of a paragraph. Consecutive chunks share up to `overlap` tokens worth
+/// of whole sentences, so that a passage cut at a chunk boundary still appears
+/// in full in one of them.
+pub fn chunk_text(
+    text: &str,
+    regions: &[Region],
+    max_tokens: usize,
+    overlap: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Vec<Chunk> {
+    let lines = LineIndex::new(text);
+    let mut chunks = vec![];
+    for region in regions {
+        let body = &text[region.start..region.end];
+        let header = match region.format {
+            Format::Table { header: true, .. } => table_header(body, max_tokens, &count_tokens),
+            _ => None,
+        };
+        let sections = match region.format {
+            Format::Text | Format::Table { .. } => vec![Section {
+                start: header.map_or(0, |(header, _)| header.len() + 1),
+                end: body.len(),
+                metadata: Metadata::new(),
+            }],
+            Format::Markdown => markdown_sections(body),
+            Format::Code => code_sections(body),
+        };
+        // Rows repeated from the previous chunk would only muddle row ranges
+        let overlap = match region.format {
+            Format::Table { .. } => 0,
+            _ => overlap,
+        };
+        let max_tokens = max_tokens - header.map_or(0, |(_, tokens)| tokens);
+        for section in sections {
+            let section = Section {
+                start: region.start + section.start,
+                end: region.start + section.end,
+                metadata: region
+                    .metadata
+                    .clone()
+                    .into_iter()
+                    .chain(section.metadata)
+                    .collect(),
+            };
+            let segments = segments(text, &section, region.format, max_tokens, &count_tokens);
+            pack(
+                &segments,
+                max_tokens,
+                overlap,
+                &mut |segments, token_count| {
+                    let start = segments[0].start;
+                    let end = segments[segments.len() - 1].end;
+                    let mut metadata = section.metadata.clone();
+                    if let Format::Table { first_row, .. } = region.format {
+                        let row = |offset| {
+                            first_row + lines.line_at(offset) - lines.line_at(region.start)
+                        };
+                        metadata.insert("rows".into(), json!([row(start), row(end)]));
+                    }
+                    let (chunk_text, token_count) = match header {
+                        Some((header, tokens)) => (
+                            format!("{header}\n{}", &text[start..end]),
+                            token_count + tokens,
+                        ),
+                        None => (text[start..end].to_string(), token_count),
+                    };
+                    chunks.push(Chunk {
+                        text: chunk_text,
+                        byte_start: start,
+                        byte_end: end,
+                        line_start: lines.line_at(start),
+                        line_end: lines.line_at(end),
+                        token_count,
+                        metadata,
+                    });
+                },
+            );
+        }
+    }
+    chunks
+}
+
+/// Returns a short description of where a chunk is in its document, from its
+/// metadata.
+pub fn describe(metadata: &Metadata) -> Option<String> {
+    let mut parts = vec![];
+    if let Some(subject) = metadata.get("subject").and_then(Value::as_str) {
+        parts.push(format!("\"{subject}\""));
+    }
+    if let Some(from) = metadata.get("from").and_then(Value::as_str) {
+        parts.push(format!("from {from}"));
+    }
+    if let Some(date) = metadata.get("date").and_then(Value::as_str) {
+        // The day is enough to place a message
+        parts.push(date.get(..10).unwrap_or(date).to_string());
+    }
+    if let Some(attachment) = metadata.get("attachment").and_then(Value::as_str) {
+        parts.push(format!("attachment {attachment}"));
+    }
+    if let Some(page) = metadata.get("page").and_then(Value::as_u64) {
+        parts.push(format!("page {page}"));
+    }
+    if let Some(cell) = metadata.get("cell").and_then(Value::as_u64) {
+        parts.push(format!("cell {cell}"));
+    }
+    if let Some(sheet) = metadata.get("sheet").and_then(Value::as_str) {
+        parts.push(format!("sheet {sheet}"));
+    }
+    if let Some([first, last]) = metadata
+        .get("rows")
+        .and_then(|r| serde_json::from_value::<[u64; 2]>(r.clone()).ok())
+    {
+        parts.push(format!("rows {first}-{last}"));
+    }
+    let headings: Vec<&str> = match metadata.get("headings") {
+        Some(Value::Array(headings)) => headings.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    if let Some(chapter) = metadata.get("chapter").and_then(Value::as_str) {
+        // Chapters usually open with their title as a heading
+        if headings.first() != Some(&chapter) {
+            parts.push(chapter.to_string());
+        }
+    }
+    if !headings.is_empty() {
+        parts.push(headings.join(" › "));
+    }
+    if let Some(symbol) = metadata.get("symbol").and_then(Value::as_str) {
+        parts.push(match metadata.get("kind").and_then(Value::as_str) {
+            Some(kind) => format!("{kind} {symbol}"),
+            None => symbol.to_string(),
+        });
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(", "))
+    }
+}
+
+/// Returns the header row of a table and its token count, if it is followed by
+/// other rows and short enough to repeat in every chunk.
+fn table_header(
+    body: &str,
+    max_tokens: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Option<(&str, usize)> {
+    let (header, rows) = body.split_once('\n')?;
+    if rows.trim().is_empty() {
+        return None;
+    }
+    let tokens = count_tokens(header) + 1;
+    (tokens <= max_tokens / 2).then_some((header, tokens))
+}
+
+/// Groups consecutive segments into chunks, calling `emit` with the segments
+/// and token count of each one.
+fn pack(
+    segments: &[Segment],
+    max_tokens: usize,
+    overlap: usize,
+    emit: &mut impl FnMut(&[Segment], usize),
+) {
+    let mut current: Vec<Segment> = vec![];
+    let mut tokens = 0;
+    // Number of segments at the start of `current` repeated from the previous
+    // chunk
+    let mut repeated = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        if tokens + segment.tokens > max_tokens {
+            if current.len() > repeated {
+                emit(&current, tokens);
+                current = overlap_tail(&current, overlap);
+                tokens = current.iter().map(|s| s.tokens).sum();
+            }
+            if tokens + segment.tokens > max_tokens {
+                current.clear();
+                tokens = 0;
+            }
+            repeated = current.len();
+        }
+        current.push(*segment);
+        tokens += segment.tokens;
+
+        // Rather than starting a paragraph that won't fit, end the chunk here
+        // if it is reasonably full already
+        if segment.paragraph_end && tokens >= max_tokens / 2 {
+            let next_paragraph: usize = segments[i + 1..]
+                .iter()
+                .scan(false, |done, s| {
+                    if *done {
+                        return None;
+                    }
+                    *done = s.paragraph_end;
+                    Some(s.tokens)
+                })
+                .sum();
+            if next_paragraph > 0 && tokens + next_paragraph > max_tokens {
+                emit(&current, tokens);
+                current = overlap_tail(&current, overlap);
+                tokens = current.iter().map(|s| s.tokens).sum();
+                repeated = current.len();
+            }
+        }
+    }
+    if current.len() > repeated {
+        emit(&current, tokens);
+    }
+}
+
+/// Returns the trailing segments of `chunk` that fit in `overlap` tokens,
+/// never including the first one, so that the next chunk always moves ahead.
+fn overlap_tail(chunk: &[Segment], overlap: usize) -> Vec<Segment> {
+    let mut tokens = 0;
+    let mut start = chunk.len();
+    while start > 1 && tokens + chunk[start - 1].tokens <= overlap {
+        start -= 1;
+        tokens += chunk[start].tokens;
+    }
+    chunk[start..].to_vec()
+}
+
+/// Splits a section into sentences, or lines for code, splitting those longer
+/// than `max_tokens` further at word boundaries.
+fn segments(
+    text: &str,
+    section: &Section,
+    format: Format,
+    max_tokens: usize,
+    count_tokens: &impl Fn(&str) -> usize,
+) -> Vec<Segment> {
+    let mut units = vec![];
+    for (p_start, p_end) in paragraphs(text, section.start, section.end) {
+        let parts = match format {
+            Format::Code | Format::Table { .. } => code_lines(text, p_start, p_end),
+            Format::Text | Format::Markdown => sentences(text, p_start, p_end),
+        };
+        let n = parts.len();
+        units.extend(
+            parts
+                .into_iter()
+                .enumerate()
+                .map(|(i, (start, end))| (start, end, i + 1 == n)),
+        );
+    }
+
+    let mut segments = vec![];
+    for (start, end, paragraph_end) in units {
+        let tokens = count_tokens(&text[start..end]);
+        if tokens <= max_tokens {
+            segments.push(Segment {
+                start,
+                end,
+                tokens,
+                paragraph_end,
+            });
+            continue;
+        }
+        let pieces = split_words(text, start, end, max_tokens, count_tokens);
+        let n_pieces = pieces.len();
+        segments.extend(pieces.into_iter().enumerate().map(|(j, mut s)| {
+            s.paragraph_end = paragraph_end && j + 1 == n_pieces;
+            s
+        }));
+    }
+    segments
+}
+
+/// Returns the byte ranges of the paragraphs of `text[start..end]`, i.e. runs
+/// of lines separated by blank lines. Paragraphs start at the beginning of
+/// their first line, indentation included.
+fn paragraphs(text: &str, start: usize, end: usize) -> Vec<(usize, usize)> {
+    let mut paragraphs = vec![];
+    let mut paragraph_start: Option<usize> = None;
+    let mut paragraph_end = start;
+    let mut offset = start;
+    for line in text[start..end].split_inclusive('\n') {
+        if line.trim().is_empty() {
+            if let Some(s) = paragraph_start.take() {
+                paragraphs.push((s, paragraph_end));
+            }
+        } else {
+            paragraph_start.get_or_insert(offset);
+            paragraph_end = offset + line.trim_end().len();
+        }
+        offset += line.len();
+    }
+    if let Some(s) = paragraph_start {
+        paragraphs.push((s, paragraph_end));
+    }
+    paragraphs
+}
+
+/// Returns the byte ranges of the sentences in `text[start..end]`. A sentence
+/// ends with `.`, `!` or `?`, and any closing quotes or brackets, followed by
+/// whitespace.
+fn sentences(text: &str, start: usize, end: usize) -> Vec<(usize, usize)> {
+    let mut sentences = vec![];
+    let mut chars = text[start..end].char_indices().peekable();
+    while chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+        chars.next();
+    }
+    let mut sentence_start = chars.peek().map_or(end, |&(j, _)| start + j);
+    while let Some((i, c)) = chars.next() {
+        if !matches!(c, '.' | '!' | '?') {
+            continue;
+        }
+        let mut sentence_end = start + i + c.len_utf8();
+        while let Some(&(j, c)) = chars.peek() {
+            if matches!(
+                c,
+                '.' | '!' | '?' | '"' | '\'' | ')' | ']' | '”' | '’' | '»'
+            ) {
+                sentence_end = start + j + c.len_utf8();
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        if chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+            sentences.push((sentence_start, sentence_end));
+            while chars.peek().is_some_and(|&(_, c)| c.is_whitespace()) {
+                chars.next();
+            }
+            sentence_start = chars.peek().map_or(end, |&(j, _)| start + j);
+        }
+    }
+    if sentence_start < end {
+        sentences.push((sentence_start, end));
+    }
+    sentences
+}
+
+/// Returns the byte ranges of the lines in `text[start..end]`, indentation
+/// included.
+fn code_lines(text: &str, start: usize, end: usize) -> Vec<(usize, usize)> {
+    let mut lines = vec![];
+    let mut offset = start;
+    for line in text[start..end].split_inclusive('\n') {
+        let trimmed = line.trim_end();
+        if !trimmed.is_empty() {
+            lines.push((offset, offset + trimmed.len()));
+        }
+        offset += line.len();
+    }
+    lines
+}
+
+/// Splits `text[start..end]` into pieces of whole words holding at most
+/// `max_tokens` tokens each. A single word longer than `max_tokens` becomes a
+/// piece of its own.
+fn split_words(
+    text: &str,
+    start: usize,
+    end: usize,
+    max_tokens: usize,
+    count_tokens: &impl Fn(&str) -> usize,
+) -> Vec<Segment> {
+    let mut pieces = vec![];
+    let mut piece: Option<Segment> = None;
+    for word in text[start..end].split_whitespace() {
+        let word_start = word.as_ptr() as usize - text.as_ptr() as usize;
+        let word_end = word_start + word.len();
+        let tokens = count_tokens(word);
+        match piece.as_mut() {
+            Some(p) if p.tokens + tokens <= max_tokens => {
+                p.end = word_end;
+                p.tokens += tokens;
+            }
+            _ => {
+                pieces.extend(piece.take());
+                piece = Some(Segment {
+                    start: word_start,
+                    end: word_end,
+                    tokens,
+                    paragraph_end: false,
+                });
+            }
+        }
+    }
+    pieces.extend(piece);
+    pieces
+}
+
+/// Splits Markdown at each heading. Sections start at their heading and carry
+/// the titles of the enclosing headings, outermost first.
+fn markdown_sections(text: &str) -> Vec<Section> {
+    let mut sections = vec![Section {
+        start: 0,
+        end: text.len(),
+        metadata: Metadata::new(),
+    }];
+    let mut headings: Vec<(usize, String)> = vec![];
+    let mut fence: Option<&str> = None;
+    let mut front_matter = text.starts_with("---\n") || text.starts_with("---\r\n");
+    // Previous line, if it could be the text of a setext heading
+    let mut previous: Option<(usize, &str)> = None;
+    let mut offset = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+
+        if front_matter {
+            front_matter = i == 0 || trimmed != "---";
+            continue;
+        }
+        if let Some(f) = fence {
+            if trimmed.starts_with(f) {
+                fence = None;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fence = Some(&trimmed[..3]);
+            previous = None;
+            continue;
+        }
+
+        let heading = match (atx_heading(line), previous) {
+            (Some((level, title)), _) => Some((line_start, level, title)),
+            (None, Some((start, title))) if is_setext_underline(line) => {
+                let level = if trimmed.starts_with('=') { 1 } else { 2 };
+                Some((start, level, title))
+            }
+            _ => None,
+        };
+        match heading {
+            Some((start, level, title)) => {
+                headings.retain(|(l, _)| *l < level);
+                headings.push((level, title.to_string()));
+                sections.last_mut().unwrap().end = start;
+                let mut metadata = Metadata::new();
+                let path: Vec<&str> = headings.iter().map(|(_, t)| t.as_str()).collect();
+                metadata.insert("headings".into(), json!(path));
+                sections.push(Section {
+                    start,
+                    end: text.len(),
+                    metadata,
+                });
+                previous = None;
+            }
+            None if trimmed.is_empty() => previous = None,
+            None => previous = Some((line_start, trimmed)),
+        }
+    }
+    sections.retain(|s| !text[s.start..s.end].trim().is_empty());
+    sections
+}
+
+/// Parses a `#`-style heading into its level and title.
+fn atx_heading(line: &str) -> Option<(usize, &str)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let level = trimmed.bytes().take_while(|&b| b == b'#').count();
+    let rest = &trimmed[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t', '\r', '\n']))
+    {
+        return None;
+    }
+    // A closing sequence of `#`s only counts as such after a space or tab, so
+    // that `# Intro to C#` keeps its title
+    let title = rest.trim();
+    let unclosed = title.trim_end_matches('#');
+    let title = if unclosed.is_empty() || unclosed.ends_with([' ', '\t']) {
+        unclosed.trim_end()
+    } else {
+        title
+    };
+    Some((level, title))
+}
+
+fn is_setext_underline(line: &str) -> bool {
+    let trimmed = line.trim();
+    !trimmed.is_empty()
+        && line.len() - line.trim_start().len() <= 3
+        && (trimmed.bytes().all(|b| b == b'=') || trimmed.bytes().all(|b| b == b'-'))
+}
+
+/// Keywords introducing a symbol worth a section of its own.
+const SYMBOL_KEYWORDS: &[&str] = &[
+    "fn",
+    "struct",
+    "enum",
+    "trait",
+    "impl",
+    "mod",
+    "union",
+    "macro_rules!",
+    "class",
+    "interface",
+    "def",
+    "defp",
+    "defmodule",
+    "function",
+    "func",
+    "module",
+    "object",
+    "protocol",
+    "extension",
+    "namespace",
+];
+
+/// Words that may come before a symbol keyword.
+const SYMBOL_MODIFIERS: &[&str] = &[
+    "pub",
+    "export",
+    "default",
+    "async",
+    "unsafe",
+    "const",
+    "extern",
+    "static",
+    "public",
+    "private",
+    "protected",
+    "internal",
+    "abstract",
+    "final",
+    "sealed",
+    "open",
+    "override",
+    "inline",
+    "data",
+    "declare",
+    "local",
+];
+
+/// Splits source code at each function, class, module and the like,
+/// recognized by the keyword starting their first line, or by the return type
+/// of functions in the C family. Sections start at any
+/// comments or attributes directly above the symbol, and carry the symbol's
+/// name, qualified by the symbols enclosing it as guessed from indentation.
+fn code_sections(text: &str) -> Vec<Section> {
+    let mut sections = vec![Section {
+        start: 0,
+        end: text.len(),
+        metadata: Metadata::new(),
+    }];
+    let mut scopes: Vec<(usize, String)> = vec![];
+    // Start of the comments and attributes directly above the current line
+    let mut preamble: Option<usize> = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            preamble = None;
+            continue;
+        }
+        let Some((kind, name)) = symbol(trimmed) else {
+            if is_comment_or_attribute(trimmed) {
+                preamble.get_or_insert(line_start);
+            } else {
+                preamble = None;
+            }
+            continue;
+        };
+        let indent: usize = line
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .map(|c| if c == '\t' { 4 } else { 1 })
+            .sum();
+        scopes.retain(|(i, _)| *i < indent);
+        scopes.push((indent, name));
+        let start = preamble.take().unwrap_or(line_start);
+        sections.last_mut().unwrap().end = start;
+        let path: Vec<&str> = scopes.iter().map(|(_, n)| n.as_str()).collect();
+        let mut metadata = Metadata::new();
+        metadata.insert("symbol".into(), json!(path.join(".")));
+        metadata.insert("kind".into(), json!(kind));
+        sections.push(Section {
+            start,
+            end: text.len(),
+            metadata,
+        });
+    }
+    sections.retain(|s| !text[s.start..s.end].trim().is_empty());
+    sections
+}
+
+/// Recognizes the first line of a symbol definition, returning the keyword,
+/// or `function` for functions led by their return type, and the symbol's
+/// name.
+fn symbol(line: &str) -> Option<(&'static str, String)> {
+    let mut words = line.split_whitespace();
+    let word = words
+        .find(|w| !SYMBOL_MODIFIERS.contains(w) && !w.starts_with("pub(") && !w.starts_with('"'))?;
+    // The keyword may run into generics, as in `impl<T>`
+    let keyword = word.split(['<', '(']).next().unwrap_or(word);
+    let Some(&kind) = SYMBOL_KEYWORDS.iter().find(|k| **k == keyword) else {
+        return typed_function(line).map(|name| ("function", name));
+    };
+    let rest = line[keyword.as_ptr() as usize - line.as_ptr() as usize + keyword.len()..].trim();
+    let name = match kind {
+        // `impl<T> Trait for Type<T> {` is named after `Type`
+        "impl" => {
+            let rest = if rest.starts_with('<') {
+                skip_balanced(rest, '<', '>')
+            } else {
+                rest
+            };
+            identifier(rest.rsplit(" for ").next().unwrap_or(rest).trim_start())
+        }
+        // Go methods, `func (r *Receiver) Name(`
+        "func" if rest.starts_with('(') => identifier(skip_balanced(rest, '(', ')').trim_start()),
+        _ => identifier(rest),
+    };
+    if name.is_empty() {
+        None
+    } else {
+        Some((kind, name))
+    }
+}
+
+/// Words that make a line with parentheses a statement rather than a function
+/// definition, as in `else if (` or `return f(`.
+const STATEMENT_KEYWORDS: &[&str] = &[
+    "if", "else", "for", "foreach", "while", "switch", "case", "do", "return", "new", "throw",
+    "catch", "sizeof", "delete", "await", "yield", "goto", "typedef", "using", "lock",
+];
+
+/// Recognizes the first line of a function or method of the C family, which
+/// starts with its return type, as in `int main(void) {` or
+/// `public List<String> names() throws IOException`, returning its name.
+fn typed_function(line: &str) -> Option<String> {
+    let head = &line[..line.find('(')?];
+    let words: Vec<&str> = head.split_whitespace().collect();
+    let type_or_name =
+        |c: char| c.is_alphanumeric() || c.is_whitespace() || "_:<>,*&[]~.?".contains(c);
+    if words.len() < 2
+        || words.iter().any(|w| STATEMENT_KEYWORDS.contains(w))
+        || !head.chars().all(type_or_name)
+    {
+        return None;
+    }
+    // Parameters are followed by qualifiers and the opening brace, if any,
+    // unlike declarations and calls
+    let tail = skip_balanced(&line[head.len()..], '(', ')').trim();
+    if tail.contains([';', '=']) || !(tail.is_empty() || tail.ends_with('{')) {
+        return None;
+    }
+    let name = identifier(words.last()?.trim_start_matches(['*', '&']));
+    (!name.is_empty()).then_some(name)
+}
+
+/// Returns the leading identifier of `s`, including dots and colons as in
+/// `self.method` or `Module::function`.
+fn identifier(s: &str) -> String {
+    s.chars()
+        .take_while(|c| c.is_alphanumeric() || matches!(c, '_' | '$' | '.' | ':' | '!'))
+        .collect::<String>()
+        .trim_end_matches([':', '.'])
+        .to_string()
+}
+
+/// Skips the bracketed group at the start of `s`.
+fn skip_balanced(s: &str, open: char, close: char) -> &str {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return &s[i + 1..];
+            }
+        }
+    }
+    ""
+}
+
+fn is_comment_or_attribute(line: &str) -> bool {
+    ["//", "/*", "*", "#", "@", "--", ";", "\"\"\""]
+        .iter()
+        .any(|p| line.starts_with(p))
+}
+
+/// Maps byte offsets to 1-based line numbers.
+struct LineIndex {
+    /// Byte offset of the start of each line.
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(text: &str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { starts }
+    }
+
+    fn line_at(&self, offset: usize) -> usize {
+        self.starts.partition_point(|&s| s <= offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(s: &str) -> usize {
+        s.split_whitespace().count()
+    }
+
+    fn chunk(text: &str, format: Format, max_tokens: usize, overlap: usize) -> Vec<Chunk> {
+        chunk_text(
+            text,
+            &[Region::whole(text, format)],
+            max_tokens,
+            overlap,
+            words,
+        )
+    }
+
+    fn slices<'a>(text: &'a str, ranges: &[(usize, usize)]) -> Vec<&'a str> {
+        ranges.iter().map(|&(s, e)| &text[s..e]).collect()
+    }
+
+    #[test]
+    fn splits_sentences() {
+        let text = "  One two. \"Three?\" Four!\nFive 3.5 six";
+        assert_eq!(
+            slices(text, &sentences(text, 0, text.len())),
+            ["One two.", "\"Three?\"", "Four!", "Five 3.5 six"]
+        );
+    }
+
+    #[test]
+    fn splits_paragraphs() {
+        let text = "a\nb  \n\n \n  c\n";
+        assert_eq!(
+            slices(text, &paragraphs(text, 0, text.len())),
+            ["a\nb", "  c"]
+        );
+    }
+
+    #[test]
+    fn chunks_stay_within_max_tokens_and_overlap() {
+        let text = "a b. c d. e f. g h. i j. k l.";
+        let chunks = chunk(text, Format::Text, 4, 2);
+        let texts: Vec<_> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "a b. c d.",
+                "c d. e f.",
+                "e f. g h.",
+                "g h. i j.",
+                "i j. k l."
+            ]
+        );
+        for c in &chunks {
+            assert!(c.token_count <= 4);
+            assert_eq!(&text[c.byte_start..c.byte_end], c.text);
+        }
+    }
+
+    #[test]
+    fn chunks_without_overlap_are_disjoint() {
+        let text = "a b. c d. e f. g h.";
+        let chunks = chunk(text, Format::Text, 4, 0);
+        let texts: Vec<_> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["a b. c d.", "e f. g h."]);
+    }
+
+    #[test]
+    fn prefers_ending_chunks_at_paragraphs() {
+        let text = "a b c.\n\nd e. f g.";
+        let chunks = chunk(text, Format::Text, 5, 0);
+        let texts: Vec<_> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["a b c.", "d e. f g."]);
+        assert_eq!((chunks[1].line_start, chunks[1].line_end), (3, 3));
+    }
+
+    #[test]
+    fn splits_long_sentences_at_words() {
+        let text = "one two three four five six seven";
+        let chunks = chunk(text, Format::Text, 3, 0);
+        let texts: Vec<_> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["one two three", "four five six", "seven"]);
+    }
+
+    fn headings(chunk: &Chunk) -> Vec<&str> {
+        chunk.metadata["headings"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|h| h.as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn repeats_table_headers() {
+        let text = "id | name\n1 | a\n2 | b\n3 | c\n4 | d";
+        let format = Format::Table {
+            first_row: 1,
+            header: true,
+        };
+        let chunks = chunk(text, format, 10, 3);
+        let texts: Vec<_> = chunks.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            ["id | name\n1 | a\n2 | b", "id | name\n3 | c\n4 | d"]
+        );
+        let rows: Vec<_> = chunks.iter().map(|c| &c.metadata["rows"]).collect();
+        assert_eq!(rows, [&json!([2, 3]), &json!([4, 5])]);
+        // Offsets locate the rows, not the repeated header
+        assert_eq!(
+            &text[chunks[1].byte_start..chunks[1].byte_end],
+            "3 | c\n4 | d"
+        );
+        assert!(chunks.iter().all(|c| c.token_count <= 10));
+    }
+
+    #[test]
+    fn repeats_only_short_headers_followed_by_rows() {
+        assert_eq!(table_header("a | b\n1 | 2", 10, words), Some(("a | b", 4)));
+        assert_eq!(table_header("a | b\n\n", 10, words), None);
+        assert_eq!(table_header("a | b", 10, words), None);
+        assert_eq!(table_header("a | b | c\n1", 8, words), None);
+    }
+
+    #[test]
+    fn parses_atx_headings() {
+        assert_eq!(atx_heading("# Title\n"), Some((1, "Title")));
+        assert_eq!(atx_heading("   ### Title ###\n"), Some((3, "Title")));
+        assert_eq!(atx_heading("## Intro to C#"), Some((2, "Intro to C#")));
+        assert_eq!(atx_heading("# Title #\t"), Some((1, "Title")));
+        assert_eq!(atx_heading("# ###"), Some((1, "")));
+        assert_eq!(atx_heading("#"), Some((1, "")));
+        assert_eq!(atx_heading("#hashtag"), None);
+        assert_eq!(atx_heading("####### Seven"), None);
+        assert_eq!(atx_heading("    # Indented code"), None);
+    }
+
+    #[test]
+    fn tags_markdown_chunks_with_headings() {
+        let text = "\
+---
+title: # not a heading
+---
+Intro.
+
+# One
+
+Text one.
+
+```
+# not a heading either
+```
+
+## Two
+Text two.
+
+Three
+-----
+Text three.
+";
+        let chunks = chunk(text, Format::Markdown, 100, 0);
+        assert_eq!(chunks.len(), 4);
+        assert!(!chunks[0].metadata.contains_key("headings"));
+        assert_eq!(headings(&chunks[1]), ["One"]);
+        assert!(chunks[1].text.contains("# not a heading either"));
+        assert_eq!(headings(&chunks[2]), ["One", "Two"]);
+        assert_eq!(headings(&chunks[3]), ["One", "Three"]);
+        assert_eq!(chunks[3].text, "Three\n-----\nText three.");
+    }
+
+    #[test]
+    fn recognizes_symbols() {
+        assert_eq!(
+            symbol("pub(crate) async fn run() {"),
+            Some(("fn", "run".into()))
+        );
+        assert_eq!(
+            symbol("impl<T: Clone> Display for Wrapper<T> {"),
+            Some(("impl", "Wrapper".into()))
+        );
+        assert_eq!(
+            symbol("func (s *Server) Serve(l net.Listener) error {"),
+            Some(("func", "Serve".into()))
+        );
+        assert_eq!(
+            symbol("class Parser(Base):"),
+            Some(("class", "Parser".into()))
+        );
+        assert_eq!(symbol("let fn_name = 1;"), None);
+    }
+
+    #[test]
+    fn recognizes_c_family_functions() {
+        let function = |name: &str| Some(("function", name.to_string()));
+        assert_eq!(symbol("int main(void) {"), function("main"));
+        assert_eq!(symbol("static char *copy(const char *s)"), function("copy"));
+        assert_eq!(
+            symbol("std::vector<int> Parser::parse(const std::string& s) const {"),
+            function("Parser::parse")
+        );
+        assert_eq!(
+            symbol("public static <T> List<T> of(T... items) throws IOException {"),
+            function("of")
+        );
+        for statement in [
+            "} else if (x) {",
+            "else if (x) {",
+            "return copy(s);",
+            "int n = count(s);",
+            "printf(\"%d\", n);",
+            "int count(const char *s);",
+            "while (n > 0) {",
+        ] {
+            assert_eq!(symbol(statement), None, "{statement}");
+        }
+    }
+
+    #[test]
+    fn tags_java_chunks_with_symbols() {
+        let text = "\
+package app;
+
+public class Server {
+    private int port;
+
+    @Override
+    public void run() {
+        listen(port);
+    }
+}
+";
+        let chunks = chunk(text, Format::Code, 100, 0);
+        let symbols: Vec<_> = chunks
+            .iter()
+            .map(|c| c.metadata.get("symbol").and_then(|s| s.as_str()))
+            .collect();
+        assert_eq!(symbols, [None, Some("Server"), Some("Server.run")]);
+        assert!(chunks[2].text.starts_with("    @Override"));
+        assert_eq!(chunks[2].metadata["kind"], "function");
+    }
+
+    #[test]
+    fn tags_code_chunks_with_symbols() {
+        let text = "\
+use std::fmt;
+
+/// A point.
+#[derive(Debug)]
+struct Point {
+    x: i32,
+}
+
+impl Point {
+    fn norm(&self) -> i32 {
+        self.x.abs()
+    }
+}
+";
+        let chunks = chunk(text, Format::Code, 100, 0);
+        let symbols: Vec<_> = chunks
+            .iter()
+            .map(|c| c.metadata.get("symbol").and_then(|s| s.as_str()))
+            .collect();
+        assert_eq!(
+            symbols,
+            [None, Some("Point"), Some("Point"), Some("Point.norm")]
+        );
+        assert!(chunks[1].text.starts_with("/// A point."));
+        assert_eq!(chunks[3].metadata["kind"], "fn");
+        assert_eq!((chunks[3].line_start, chunks[3].line_end), (10, 13));
+    }
+}
@@ -0,0 +1,109 @@
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use anyhow::Context;
+use clap::Subcommand;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use crate::{chunker::describe, db, search, workers::dir_scanner};
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Add a directory to the index and bring the whole index up to date in
+    /// the foreground
+    Index {
+        /// Directory to index
+        dir: PathBuf,
+        /// Collection to add the directory to, created if needed [default: its current one]
+        #[arg(long)]
+        collection: Option<String>,
+    },
+    /// Search the index
+    Search {
+        query: String,
+        /// Collection to search, may be repeated [default: every collection]
+        #[arg(short, long)]
+        collection: Vec<String>,
+        /// Print results as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show indexing progress
+    Status {
+        /// Print status as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+pub fn run(command: Command, pool: Pool<SqliteConnectionManager>) -> anyhow::Result<()> {
+    match command {
+        Command::Index { dir, collection } => {
+            let dir = dir
+                .canonicalize()
+                .with_context(|| format!("unable to open {}", dir.display()))?;
+            if !dir.is_dir() {
+                anyhow::bail!("{} is not a directory", dir.display());
+            }
+            let conn = pool.get()?;
+            db::add_root(&conn, &dir, collection.as_deref())?;
+            db::rescan(&conn)?;
+            drop(conn);
+            dir_scanner(pool.clone())?;
+            print_status(&pool, false)
+        }
+        Command::Search {
+            query,
+            collection,
+            json,
+        } => {
+            let results = search::fts(Rc::new(pool.get()?), &query, &collection)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&results)?);
+            } else {
+                for r in results {
+                    let stale = if r.stale { " [stale]" } else { "" };
+                    let context = describe(&r.metadata)
+                        .map(|c| format!(" ({c})"))
+                        .unwrap_or_default();
+                    println!(
+                        "{:.3}\t[{}] {}:{}-{}{context}{stale}",
+                        r.score, r.collection, r.file_path, r.line_start, r.line_end
+                    );
+                    println!("\t{}", r.chunk.replace('\n', "\n\t"));
+                }
+            }
+            Ok(())
+        }
+        Command::Status { json } => print_status(&pool, json),
+    }
+}
+
+fn print_status(pool: &Pool<SqliteConnectionManager>, json: bool) -> anyhow::Result<()> {
+    let status = search::get_scan_status(Rc::new(pool.get()?))?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        println!("pending:  {}", status.pending);
+        println!("scanning: {}", status.scanning);
+        println!("done:     {}", status.done);
+        println!("error:    {}", status.error);
+        for collection in &status.collections {
+            println!("{}: {} chunks", collection.name, collection.chunks);
+            if let Some(model) = &collection.embedding_model {
+                println!("  model: {model}");
+            }
+            if let Some(model) = &collection.rebuilding {
+                println!("  rebuilding embeddings with {model}");
+            }
+        }
+        if !status.types.is_empty() {
+            println!("types:");
+            for (mime, count) in &status.types {
+                println!("  {count:>8}  {mime}");
+            }
+        }
+    }
+    Ok(())
+}
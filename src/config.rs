@@ -0,0 +1,551 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[cfg(test)]
+thread_local! {
+    /// Configuration of the test running on this thread, see [`set_for_tests`].
+    static TEST_CONFIG: std::cell::Cell<Option<&'static Config>> = const { std::cell::Cell::new(None) };
+}
+
+/// Name of the collection made up of the top-level roots, which also holds
+/// the roots added without naming a collection.
+pub const DEFAULT_COLLECTION: &str = "default";
+
+/// Application configuration, read from `$XDG_CONFIG_HOME/lmtools/config.toml`
+/// unless another file is given on the command line.
+///
+/// Relative paths in the file are resolved against the directory containing it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// SQLite database holding the work queues and the index.
+    pub database: PathBuf,
+    /// Directories to index, into the default collection.
+    pub roots: Vec<RootConfig>,
+    pub models: ModelsConfig,
+    pub indexing: IndexingConfig,
+    /// Collections indexed and searched apart from the default one, by name.
+    pub collections: BTreeMap<String, CollectionConfig>,
+}
+
+/// A directory to index, given either as a path or as a table with the rules
+/// for the files below it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "RootEntry")]
+pub struct RootConfig {
+    pub path: PathBuf,
+    /// Globs of the files to index, relative to the root. Defaults to every
+    /// file.
+    pub include: Vec<String>,
+    /// Globs of the files and directories to leave out, relative to the root.
+    pub exclude: Vec<String>,
+    /// Overrides `indexing.hidden` for this root.
+    pub hidden: Option<bool>,
+    /// Overrides `indexing.max_file_size` for this root.
+    pub max_file_size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RootEntry {
+    Path(PathBuf),
+    Table(RootTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RootTable {
+    path: PathBuf,
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    hidden: Option<bool>,
+    max_file_size: Option<u64>,
+}
+
+impl RootConfig {
+    /// A root following the default rules.
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            include: vec![],
+            exclude: vec![],
+            hidden: None,
+            max_file_size: None,
+        }
+    }
+}
+
+impl From<RootEntry> for RootConfig {
+    fn from(entry: RootEntry) -> Self {
+        match entry {
+            RootEntry::Path(path) => Self::new(path),
+            RootEntry::Table(t) => Self {
+                path: t.path,
+                include: t.include,
+                exclude: t.exclude,
+                hidden: t.hidden,
+                max_file_size: t.max_file_size,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelsConfig {
+    /// GGUF model used to embed chunks and queries.
+    pub embedding: PathBuf,
+    /// GGUF cross-encoder used to rerank search results.
+    pub reranking: PathBuf,
+}
+
+/// A collection of roots with models and chunking settings of its own. Those
+/// it leaves out are taken from the top level.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollectionConfig {
+    pub roots: Vec<RootConfig>,
+    pub models: CollectionModelsConfig,
+    /// Overrides `indexing.chunk_size` for this collection.
+    pub chunk_size: Option<usize>,
+    /// Overrides `indexing.chunk_overlap` for this collection.
+    pub chunk_overlap: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollectionModelsConfig {
+    /// Overrides `models.embedding` for this collection.
+    pub embedding: Option<PathBuf>,
+    /// Overrides `models.reranking` for this collection.
+    pub reranking: Option<PathBuf>,
+}
+
+/// The settings a collection is indexed and searched with.
+#[derive(Debug, Clone, Copy)]
+pub struct CollectionSettings<'a> {
+    pub name: &'a str,
+    pub roots: &'a [RootConfig],
+    pub embedding: &'a Path,
+    pub reranking: &'a Path,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexingConfig {
+    /// Threads used by llama.cpp, per worker. Defaults to the number of
+    /// available cores.
+    pub threads: Option<usize>,
+    /// Files indexed in parallel. Defaults to 1.
+    pub workers: Option<usize>,
+    /// Target number of tokens per chunk. Capped at the embedding model's
+    /// training context length. Defaults to 256.
+    pub chunk_size: Option<usize>,
+    /// Tokens of whole sentences repeated at the start of a chunk from the end
+    /// of the previous one. Defaults to 32, or a quarter of smaller chunks.
+    pub chunk_overlap: Option<usize>,
+    /// Attempts at a file or directory before it is left in the `error` state.
+    /// Defaults to 3.
+    pub max_attempts: Option<u32>,
+    /// Seconds after which a file or directory left in the `scanning` state,
+    /// e.g. by a crash, is handed out again. Workers renew their claims on
+    /// files that take longer. Defaults to 600.
+    pub lease_timeout: Option<u64>,
+    /// Whether hidden files and directories are indexed. Defaults to false.
+    pub hidden: Option<bool>,
+    /// Size in bytes above which files other than mailboxes are not indexed.
+    /// Defaults to 64 MiB.
+    pub max_file_size: Option<u64>,
+    /// Levels of archives unpacked, counting archives nested in archives; 0
+    /// leaves archives alone. Defaults to 3.
+    pub max_archive_depth: Option<usize>,
+    /// Decompressed bytes unpacked from an archive or mailbox, including the
+    /// archives nested in it, before it is given up on. Defaults to 1 GiB.
+    pub max_archive_size: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            database: data_dir()
+                .map(|d| d.join("data.sqlite"))
+                .unwrap_or_else(|| PathBuf::from("data.sqlite")),
+            roots: vec![],
+            models: ModelsConfig::default(),
+            indexing: IndexingConfig::default(),
+            collections: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for ModelsConfig {
+    fn default() -> Self {
+        Self {
+            embedding: PathBuf::from("./models/all-minilm-l6-v2-q4_k_m.gguf"),
+            reranking: PathBuf::from("./models/jina-reranker-v1-tiny-en.Q8_0.gguf"),
+        }
+    }
+}
+
+impl IndexingConfig {
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.unwrap_or(1)
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.unwrap_or(256)
+    }
+
+    pub fn chunk_overlap(&self) -> usize {
+        self.chunk_overlap
+            .unwrap_or_else(|| default_overlap(self.chunk_size()))
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts.unwrap_or(3)
+    }
+
+    pub fn lease_timeout(&self) -> u64 {
+        self.lease_timeout.unwrap_or(600)
+    }
+
+    pub fn hidden(&self) -> bool {
+        self.hidden.unwrap_or(false)
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size.unwrap_or(64 << 20)
+    }
+
+    pub fn max_archive_depth(&self) -> usize {
+        self.max_archive_depth.unwrap_or(3)
+    }
+
+    pub fn max_archive_size(&self) -> u64 {
+        self.max_archive_size.unwrap_or(1 << 30)
+    }
+}
+
+impl Config {
+    /// The settings of the collection `name`. Collections that are not in the
+    /// configuration file, such as those created from the command line, have
+    /// no roots of their own and use the top-level settings.
+    pub fn collection<'a>(&'a self, name: &'a str) -> CollectionSettings<'a> {
+        let defaults = CollectionSettings {
+            name,
+            roots: &[],
+            embedding: &self.models.embedding,
+            reranking: &self.models.reranking,
+            chunk_size: self.indexing.chunk_size(),
+            chunk_overlap: self.indexing.chunk_overlap(),
+        };
+        if name == DEFAULT_COLLECTION {
+            return CollectionSettings {
+                roots: &self.roots,
+                ..defaults
+            };
+        }
+        let Some(collection) = self.collections.get(name) else {
+            return defaults;
+        };
+        let chunk_size = collection.chunk_size.unwrap_or(defaults.chunk_size);
+        CollectionSettings {
+            roots: &collection.roots,
+            embedding: collection
+                .models
+                .embedding
+                .as_deref()
+                .unwrap_or(defaults.embedding),
+            reranking: collection
+                .models
+                .reranking
+                .as_deref()
+                .unwrap_or(defaults.reranking),
+            chunk_size,
+            // Without an overlap of its own, a collection with smaller chunks
+            // also gets a smaller default one
+            chunk_overlap: collection
+                .chunk_overlap
+                .or(self.indexing.chunk_overlap)
+                .unwrap_or_else(|| default_overlap(chunk_size)),
+            ..defaults
+        }
+    }
+
+    /// The settings of every configured collection, the default one first.
+    pub fn collections(&self) -> Vec<CollectionSettings<'_>> {
+        std::iter::once(DEFAULT_COLLECTION)
+            .chain(self.collections.keys().map(String::as_str))
+            .map(|name| self.collection(name))
+            .collect()
+    }
+
+    /// Loads the configuration from `path`, or from the default location if
+    /// `path` is `None`. A missing file at the default location is not an
+    /// error, and yields the default configuration.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (path, explicit) = match path {
+            Some(p) => (Some(p.to_path_buf()), true),
+            None => (default_path(), false),
+        };
+        let mut config = match path {
+            Some(path) if explicit || path.exists() => {
+                let text = std::fs::read_to_string(&path)
+                    .with_context(|| format!("unable to read config file {}", path.display()))?;
+                let mut config: Config = toml::from_str(&text)
+                    .with_context(|| format!("invalid config file {}", path.display()))?;
+                let base = path
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|| PathBuf::from("."));
+                config.resolve_paths(&base);
+                config
+            }
+            _ => Config::default(),
+        };
+        config.database = expand_home(&config.database);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |p: &Path| {
+            let p = expand_home(p);
+            if p.is_relative() {
+                base.join(p)
+            } else {
+                p
+            }
+        };
+        self.database = resolve(&self.database);
+        for root in &mut self.roots {
+            root.path = resolve(&root.path);
+        }
+        self.models.embedding = resolve(&self.models.embedding);
+        self.models.reranking = resolve(&self.models.reranking);
+        for collection in self.collections.values_mut() {
+            for root in &mut collection.roots {
+                root.path = resolve(&root.path);
+            }
+            let models = &mut collection.models;
+            for path in [&mut models.embedding, &mut models.reranking]
+                .into_iter()
+                .flatten()
+            {
+                *path = resolve(path);
+            }
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(dir) = self.database.parent() {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                std::fs::create_dir_all(dir).with_context(|| {
+                    format!("unable to create database directory {}", dir.display())
+                })?;
+            }
+        }
+        if self.database.is_dir() {
+            bail!("database path {} is a directory", self.database.display());
+        }
+        if self.collections.contains_key(DEFAULT_COLLECTION) {
+            bail!("collections.{DEFAULT_COLLECTION} is reserved for the top-level settings");
+        }
+        let mut seen: BTreeMap<&Path, &str> = BTreeMap::new();
+        for collection in self.collections() {
+            for root in collection.roots {
+                if !root.path.is_dir() {
+                    bail!(
+                        "root {} does not exist or is not a directory",
+                        root.path.display()
+                    );
+                }
+                let mut globs = ignore::overrides::OverrideBuilder::new(&root.path);
+                for glob in root.include.iter().chain(&root.exclude) {
+                    globs.add(glob).with_context(|| {
+                        format!("invalid glob {glob:?} in root {}", root.path.display())
+                    })?;
+                }
+                if let Some(other) = seen.insert(&root.path, collection.name) {
+                    if other != collection.name {
+                        bail!(
+                            "root {} is in both the {other} and the {} collections",
+                            root.path.display(),
+                            collection.name
+                        );
+                    }
+                }
+            }
+            for (name, model) in [
+                ("embedding", collection.embedding),
+                ("reranking", collection.reranking),
+            ] {
+                if !model.is_file() {
+                    bail!("{name} model {} does not exist", model.display());
+                }
+            }
+            // Errors name the setting as it was written, in the collection or
+            // inherited from `indexing`
+            let overrides = self.collections.get(collection.name);
+            let key = |key, overridden: bool| {
+                if overridden {
+                    format!("collections.{}.{key}", collection.name)
+                } else {
+                    format!("indexing.{key}")
+                }
+            };
+            let size_key = key(
+                "chunk_size",
+                overrides.is_some_and(|c| c.chunk_size.is_some()),
+            );
+            let overlap_key = key(
+                "chunk_overlap",
+                overrides.is_some_and(|c| c.chunk_overlap.is_some()),
+            );
+            if collection.chunk_size == 0 {
+                bail!("{size_key} must be greater than 0");
+            }
+            // Default overlaps always fit
+            if collection.chunk_overlap >= collection.chunk_size {
+                bail!("{overlap_key} must be less than {size_key}");
+            }
+        }
+        if self.indexing.threads == Some(0) {
+            bail!("indexing.threads must be greater than 0");
+        }
+        if self.indexing.workers == Some(0) {
+            bail!("indexing.workers must be greater than 0");
+        }
+        if self.indexing.max_attempts == Some(0) {
+            bail!("indexing.max_attempts must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+/// Installs the process-wide configuration. Must be called once at startup.
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        panic!("config already initialized");
+    }
+}
+
+pub fn get() -> &'static Config {
+    #[cfg(test)]
+    if let Some(config) = TEST_CONFIG.get() {
+        return config;
+    }
+    CONFIG.get().expect("config not initialized")
+}
+
+/// Makes [`get`] return `config` on the current thread, so that tests running
+/// alongside each other can use configurations of their own.
+#[cfg(test)]
+pub fn set_for_tests(config: Config) -> &'static Config {
+    let config = Box::leak(Box::new(config));
+    TEST_CONFIG.set(Some(config));
+    config
+}
+
+/// Overlap of chunks of `chunk_size` tokens when none is configured.
+fn default_overlap(chunk_size: usize) -> usize {
+    (chunk_size / 4).min(32)
+}
+
+fn default_path() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config").map(|d| d.join("lmtools").join("config.toml"))
+}
+
+fn data_dir() -> Option<PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share").map(|d| d.join("lmtools"))
+}
+
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    match std::env::var_os(var) {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback)),
+    }
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(rest),
+            None => path.to_path_buf(),
+        },
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads the settings in `config`, from a directory that also holds the
+    /// models.
+    fn load(name: &str, config: &str) -> anyhow::Result<Config> {
+        let dir =
+            std::env::temp_dir().join(format!("lmtools-config-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for model in ["e.gguf", "r.gguf"] {
+            std::fs::write(dir.join(model), "").unwrap();
+        }
+        let path = dir.join("config.toml");
+        let config = format!(
+            "database = \"data.sqlite\"\n[models]\nembedding = \"e.gguf\"\nreranking = \"r.gguf\"\n{config}"
+        );
+        std::fs::write(&path, config).unwrap();
+        let config = Config::load(Some(&path));
+        std::fs::remove_dir_all(&dir).unwrap();
+        config
+    }
+
+    #[test]
+    fn scales_default_overlaps_to_small_chunks() {
+        let config = load(
+            "small",
+            "[indexing]\nchunk_size = 16\n[collections.smaller]\nchunk_size = 8\n[collections.large]\nchunk_size = 1024\n",
+        )
+        .unwrap();
+        let overlap = |name| config.collection(name).chunk_overlap;
+        assert_eq!(overlap(DEFAULT_COLLECTION), 4);
+        assert_eq!(overlap("smaller"), 2);
+        assert_eq!(overlap("large"), 32);
+        assert_eq!(overlap("created-from-the-command-line"), 4);
+    }
+
+    #[test]
+    fn rejects_overlaps_as_large_as_chunks() {
+        let error = |config| format!("{:#}", load("overlaps", config).unwrap_err());
+        assert_eq!(
+            error("[indexing]\nchunk_overlap = 64\n[collections.small]\nchunk_size = 32\n"),
+            "indexing.chunk_overlap must be less than collections.small.chunk_size"
+        );
+        assert_eq!(
+            error("[collections.small]\nchunk_size = 32\nchunk_overlap = 32\n"),
+            "collections.small.chunk_overlap must be less than collections.small.chunk_size"
+        );
+        assert_eq!(
+            error("[indexing]\nchunk_size = 0\n"),
+            "indexing.chunk_size must be greater than 0"
+        );
+    }
+}
@@ -0,0 +1,1522 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{
+    ffi::sqlite3_auto_extension, params, Connection, OptionalExtension, Transaction,
+    TransactionBehavior,
+};
+use sqlite_vec::sqlite3_vec_init;
+
+use crate::config::{self, DEFAULT_COLLECTION};
+
+/// The schema of version 1, the first with migrations.
+const SCHEMA_V1: &str = r#"
+-- Root directories added by the user
+CREATE TABLE IF NOT EXISTS roots (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT UNIQUE NOT NULL,
+    added_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Directories to scan
+CREATE TABLE IF NOT EXISTS dir_queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT UNIQUE NOT NULL,
+    status TEXT CHECK(status IN ('pending', 'scanning', 'done', 'error')) DEFAULT 'pending',
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    error TEXT,
+    -- Unix time at which a scanner claimed this row
+    leased_at INTEGER,
+    -- Failed attempts since the last success
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- Unix time before which a failed row is not retried
+    next_attempt_at INTEGER
+);
+
+-- Files to index
+CREATE TABLE IF NOT EXISTS file_queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    path TEXT UNIQUE NOT NULL,
+    dir_id INTEGER REFERENCES dir_queue(id) ON DELETE CASCADE,
+    status TEXT CHECK(status IN ('pending', 'scanning', 'done', 'error')) DEFAULT 'pending',
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    error TEXT,
+    -- Modification time in nanoseconds since the epoch, as last seen by the scanner
+    mtime INTEGER,
+    size INTEGER,
+    -- blake3 hash of the content that is currently indexed
+    hash TEXT,
+    -- Character encoding the indexed content was decoded from, for text files
+    encoding TEXT,
+    -- MIME type detected from the content
+    mime TEXT,
+    leased_at INTEGER,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER
+);
+
+-- Actual full-text search table
+-- Chunks hold the original text; offsets and lines locate it in the file
+CREATE VIRTUAL TABLE IF NOT EXISTS documents USING fts5(
+    file_path UNINDEXED,
+    chunk_index UNINDEXED,
+    content,
+    byte_start UNINDEXED,
+    byte_end UNINDEXED,
+    line_start UNINDEXED,
+    line_end UNINDEXED,
+    token_count UNINDEXED,
+    -- JSON object locating the chunk in the document's structure
+    metadata UNINDEXED
+);
+
+-- Vector tables holding an embedding of each chunk in `documents`, under the
+-- same rowid, one table per embedding model. Searches use the active one;
+-- after the embedding model changes, the table for the new model is built in
+-- the background and then takes over.
+CREATE TABLE IF NOT EXISTS embedding_indexes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- Name of the vec0 table
+    name TEXT UNIQUE NOT NULL,
+    -- Path of the model the embeddings come from, NULL if not known
+    model TEXT,
+    dimension INTEGER NOT NULL,
+    status TEXT CHECK(status IN ('building', 'active')) NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+"#;
+
+/// Opens the database at `path`, creating or upgrading the schema if needed.
+pub fn open_pool(path: &Path) -> anyhow::Result<Pool<SqliteConnectionManager>> {
+    load_sqlite_vec();
+
+    // Indexing workers write concurrently, wait for each other's locks
+    let manager =
+        SqliteConnectionManager::file(path).with_init(|c| c.busy_timeout(Duration::from_secs(30)));
+    let workers = config::get().indexing.workers() as u32;
+    let pool = Pool::builder()
+        .max_size(10.max(workers + 4))
+        .build(manager)?;
+    let conn = pool.get()?;
+    conn.execute_batch("PRAGMA journal_mode=WAL;")
+        .map_err(anyhow::Error::from)
+        .and_then(|_| migrate(&conn))
+        .with_context(|| format!("unable to initialize database {}", path.display()))?;
+    Ok(pool)
+}
+
+fn load_sqlite_vec() {
+    unsafe {
+        sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
+    }
+}
+
+/// Schema migrations, in order. The version of a database, stored in
+/// `PRAGMA user_version`, is the number of migrations applied to it.
+const MIGRATIONS: &[fn(&Connection) -> rusqlite::Result<()>] = &[
+    migrate_unversioned,
+    migrate_collections,
+    migrate_member_hashes,
+    migrate_lease_ids,
+];
+
+/// Applies the migrations a database is missing, each in a transaction of its
+/// own.
+pub fn migrate(conn: &Connection) -> anyhow::Result<()> {
+    loop {
+        // Another process may be migrating the same database
+        let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+        let version: usize = tx.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+        if version > MIGRATIONS.len() {
+            anyhow::bail!(
+                "schema version {version} is newer than this version of lmtools supports"
+            );
+        }
+        let Some(migration) = MIGRATIONS.get(version) else {
+            return Ok(());
+        };
+        migration(&tx)
+            .with_context(|| format!("unable to migrate to schema version {}", version + 1))?;
+        tx.pragma_update(None, "user_version", version + 1)?;
+        tx.commit()?;
+    }
+}
+
+/// Columns added to the queues before migrations, which databases created by
+/// earlier versions lack.
+const UNVERSIONED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("dir_queue", "error", "TEXT"),
+    ("dir_queue", "leased_at", "INTEGER"),
+    ("dir_queue", "attempts", "INTEGER NOT NULL DEFAULT 0"),
+    ("dir_queue", "next_attempt_at", "INTEGER"),
+    ("file_queue", "mtime", "INTEGER"),
+    ("file_queue", "size", "INTEGER"),
+    ("file_queue", "hash", "TEXT"),
+    ("file_queue", "encoding", "TEXT"),
+    ("file_queue", "mime", "TEXT"),
+    ("file_queue", "leased_at", "INTEGER"),
+    ("file_queue", "attempts", "INTEGER NOT NULL DEFAULT 0"),
+    ("file_queue", "next_attempt_at", "INTEGER"),
+];
+
+/// Brings a database created before migrations, by any earlier version, to
+/// version 1.
+fn migrate_unversioned(conn: &Connection) -> rusqlite::Result<()> {
+    // Chunks from before they kept their offsets and metadata cannot be
+    // upgraded, their files are indexed again
+    let chunk_columns = table_columns(conn, "documents")?;
+    let reindex = !chunk_columns.is_empty() && !chunk_columns.iter().any(|c| c == "metadata");
+    if reindex {
+        conn.execute_batch("DROP TABLE documents; DROP TABLE IF EXISTS embeddings;")?;
+    }
+    conn.execute_batch(SCHEMA_V1)?;
+    for (table, column, definition) in UNVERSIONED_COLUMNS {
+        if !table_columns(conn, table)?.iter().any(|c| c == column) {
+            conn.execute_batch(&format!(
+                "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+            ))?;
+        }
+    }
+    if reindex {
+        conn.execute(
+            "UPDATE file_queue SET status = 'pending', hash = NULL, attempts = 0, next_attempt_at = NULL",
+            [],
+        )?;
+    }
+    adopt_legacy_embeddings(conn)
+}
+
+/// Names of the columns of `table`, none if it does not exist.
+fn table_columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    conn.prepare("SELECT name FROM pragma_table_info(?)")?
+        .query_map([table], |r| r.get(0))?
+        .collect()
+}
+
+/// Dimension of the `embeddings` table of databases from before embedding
+/// models were recorded, which only fitted all-MiniLM-L6-v2.
+const LEGACY_DIMENSION: usize = 384;
+
+/// Moves the embeddings of a database from before embedding models were
+/// recorded into an index of their own. That table's rowids drifted apart
+/// from those of `documents`, so rows are matched by file path and chunk.
+fn adopt_legacy_embeddings(conn: &Connection) -> rusqlite::Result<()> {
+    if table_columns(conn, "embeddings")?.is_empty() {
+        return Ok(());
+    }
+    // Migrations must not change with the code that creates indexes
+    conn.execute_batch(&format!(
+        r#"
+        INSERT INTO embedding_indexes (name, dimension, status)
+            VALUES ('embeddings_legacy', {LEGACY_DIMENSION}, 'active');
+        CREATE VIRTUAL TABLE embeddings_legacy USING vec0(
+            file_path TEXT,
+            chunk_index INTEGER,
+            content TEXT,
+            +byte_start INTEGER,
+            +byte_end INTEGER,
+            +line_start INTEGER,
+            +line_end INTEGER,
+            +token_count INTEGER,
+            +metadata TEXT,
+            embedding float[{LEGACY_DIMENSION}]
+        );
+        CREATE TEMP TABLE chunk_rowids AS
+            SELECT rowid AS id, file_path, chunk_index FROM documents;
+        CREATE INDEX temp.chunk_rowids_chunk ON chunk_rowids (file_path, chunk_index);
+        INSERT INTO embeddings_legacy
+            (rowid, file_path, chunk_index, content, byte_start, byte_end, line_start, line_end, token_count, metadata, embedding)
+        SELECT d.rowid, d.file_path, d.chunk_index, d.content, d.byte_start, d.byte_end,
+            d.line_start, d.line_end, d.token_count, d.metadata, e.embedding
+        FROM embeddings e
+        JOIN chunk_rowids r USING (file_path, chunk_index)
+        JOIN documents d ON d.rowid = r.id;
+        DROP TABLE chunk_rowids;
+        DROP TABLE embeddings;
+        "#
+    ))
+}
+
+/// Version 2 puts roots, chunks and embedding indexes into collections, and
+/// everything indexed so far into the default one.
+fn migrate_collections(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        r#"
+        -- Sets of roots indexed with their own models and chunking settings,
+        -- which are searched separately or together
+        CREATE TABLE collections (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT UNIQUE NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        );
+        INSERT INTO collections (id, name) VALUES (1, 'default');
+        ALTER TABLE roots ADD COLUMN collection_id INTEGER NOT NULL DEFAULT 1;
+        ALTER TABLE embedding_indexes ADD COLUMN collection_id INTEGER NOT NULL DEFAULT 1;
+
+        -- FTS5 tables cannot gain columns, the chunks are copied over with
+        -- their rowids, which their embeddings share
+        ALTER TABLE documents RENAME TO documents_v1;
+        CREATE VIRTUAL TABLE documents USING fts5(
+            file_path UNINDEXED,
+            chunk_index UNINDEXED,
+            content,
+            byte_start UNINDEXED,
+            byte_end UNINDEXED,
+            line_start UNINDEXED,
+            line_end UNINDEXED,
+            token_count UNINDEXED,
+            metadata UNINDEXED,
+            collection_id UNINDEXED
+        );
+        INSERT INTO documents
+            (rowid, file_path, chunk_index, content, byte_start, byte_end, line_start, line_end, token_count, metadata, collection_id)
+        SELECT rowid, file_path, chunk_index, content, byte_start, byte_end, line_start, line_end, token_count, metadata, 1
+        FROM documents_v1;
+        DROP TABLE documents_v1;
+        "#,
+    )
+}
+
+/// Version 3 records the content of the members of archives and mailboxes, so
+/// that only new and changed members are indexed again when they change.
+fn migrate_member_hashes(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        r#"
+        -- blake3 hash of each indexed member of an archive or mailbox, by
+        -- virtual path
+        CREATE TABLE member_hashes (
+            file_path TEXT PRIMARY KEY,
+            hash TEXT NOT NULL
+        );
+        "#,
+    )
+}
+
+/// Version 4 tells claims on queue rows apart, so that a worker whose claim ran
+/// out does not record its work over that of the worker that took the row over.
+fn migrate_lease_ids(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        r#"
+        -- Random id of the latest claim on the row
+        ALTER TABLE dir_queue ADD COLUMN lease_id INTEGER;
+        ALTER TABLE file_queue ADD COLUMN lease_id INTEGER;
+        "#,
+    )
+}
+
+/// A named set of roots, see `collections` in the schema.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+}
+
+/// Lists the collections, in the order they were created.
+pub fn list_collections(conn: &Connection) -> rusqlite::Result<Vec<Collection>> {
+    let mut stmt = conn.prepare("SELECT id, name FROM collections ORDER BY id")?;
+    let collections = stmt
+        .query_map([], |r| {
+            Ok(Collection {
+                id: r.get(0)?,
+                name: r.get(1)?,
+            })
+        })?
+        .collect();
+    collections
+}
+
+/// Looks up the collections called `names`, or lists every collection if
+/// `names` is empty.
+pub fn find_collections(conn: &Connection, names: &[String]) -> anyhow::Result<Vec<Collection>> {
+    let collections = list_collections(conn)?;
+    if names.is_empty() {
+        return Ok(collections);
+    }
+    names
+        .iter()
+        .map(|name| {
+            collections
+                .iter()
+                .find(|c| &c.name == name)
+                .cloned()
+                .with_context(|| format!("no collection named {name}"))
+        })
+        .collect()
+}
+
+/// The collection of the innermost root containing `path`. Paths outside
+/// every root, such as those of a root removed meanwhile, are in the default
+/// collection.
+pub fn collection_of(conn: &Connection, path: &str) -> rusqlite::Result<Collection> {
+    conn.query_row(
+        r#"
+        SELECT c.id, c.name FROM collections c
+        LEFT JOIN roots r ON r.collection_id = c.id
+            AND (?1 = r.path OR substr(?1, 1, length(r.path) + 1) = r.path || '/')
+        WHERE r.id IS NOT NULL OR c.name = ?2
+        ORDER BY length(r.path) DESC
+        LIMIT 1
+        "#,
+        params![path, DEFAULT_COLLECTION],
+        |r| {
+            Ok(Collection {
+                id: r.get(0)?,
+                name: r.get(1)?,
+            })
+        },
+    )
+}
+
+/// The id of the collection `name`, which is created if it does not exist.
+fn ensure_collection(conn: &Connection, name: &str) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT OR IGNORE INTO collections (name) VALUES (?)",
+        [name],
+    )?;
+    conn.query_row("SELECT id FROM collections WHERE name = ?", [name], |r| {
+        r.get(0)
+    })
+}
+
+/// A vec0 table of embeddings, see `embedding_indexes` in the schema.
+#[derive(Clone, PartialEq, Debug)]
+pub struct EmbeddingIndex {
+    pub name: String,
+    pub collection_id: i64,
+    /// Path of the model the embeddings come from, `None` for embeddings
+    /// carried over from before models were recorded.
+    pub model: Option<String>,
+    pub dimension: usize,
+    /// Whether searches use this index, rather than it being built.
+    pub active: bool,
+}
+
+/// Lists the embedding indexes by collection, the active one first.
+pub fn embedding_indexes(conn: &Connection) -> rusqlite::Result<Vec<EmbeddingIndex>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, collection_id, model, dimension, status = 'active' FROM embedding_indexes
+        ORDER BY collection_id, status = 'active' DESC, id",
+    )?;
+    let indexes = stmt
+        .query_map([], |r| {
+            Ok(EmbeddingIndex {
+                name: r.get(0)?,
+                collection_id: r.get(1)?,
+                model: r.get(2)?,
+                dimension: r.get::<_, i64>(3)? as usize,
+                active: r.get(4)?,
+            })
+        })?
+        .collect();
+    indexes
+}
+
+fn collection_indexes(
+    conn: &Connection,
+    collection_id: i64,
+) -> rusqlite::Result<impl Iterator<Item = EmbeddingIndex>> {
+    Ok(embedding_indexes(conn)?
+        .into_iter()
+        .filter(move |i| i.collection_id == collection_id))
+}
+
+/// The index searches of the collection use.
+pub fn active_index(
+    conn: &Connection,
+    collection_id: i64,
+) -> rusqlite::Result<Option<EmbeddingIndex>> {
+    Ok(collection_indexes(conn, collection_id)?.find(|i| i.active))
+}
+
+/// The index new chunks of the collection are embedded into: the one being
+/// built if its embedding model changed, and the active one otherwise.
+pub fn target_index(
+    conn: &Connection,
+    collection_id: i64,
+) -> rusqlite::Result<Option<EmbeddingIndex>> {
+    Ok(collection_indexes(conn, collection_id)?.min_by_key(|i| i.active))
+}
+
+/// The index being built for a new embedding model of the collection, if any.
+pub fn building_index(
+    conn: &Connection,
+    collection_id: i64,
+) -> rusqlite::Result<Option<EmbeddingIndex>> {
+    Ok(collection_indexes(conn, collection_id)?.find(|i| !i.active))
+}
+
+fn create_embedding_index(
+    conn: &Connection,
+    collection_id: i64,
+    model: Option<&str>,
+    dimension: usize,
+    active: bool,
+) -> rusqlite::Result<EmbeddingIndex> {
+    let id: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(id), 0) + 1 FROM embedding_indexes",
+        [],
+        |r| r.get(0),
+    )?;
+    let index = EmbeddingIndex {
+        name: format!("embeddings_{id}"),
+        collection_id,
+        model: model.map(str::to_string),
+        dimension,
+        active,
+    };
+    conn.execute(
+        "INSERT INTO embedding_indexes (id, name, collection_id, model, dimension, status)
+        VALUES (?, ?, ?, ?, ?, ?)",
+        params![
+            id,
+            index.name,
+            collection_id,
+            index.model,
+            dimension as i64,
+            if active { "active" } else { "building" }
+        ],
+    )?;
+    conn.execute_batch(&format!(
+        r#"
+        CREATE VIRTUAL TABLE {} USING vec0(
+            file_path TEXT,
+            chunk_index INTEGER,
+            content TEXT,
+            +byte_start INTEGER,
+            +byte_end INTEGER,
+            +line_start INTEGER,
+            +line_end INTEGER,
+            +token_count INTEGER,
+            +metadata TEXT,
+            embedding float[{dimension}]
+        );
+        "#,
+        index.name
+    ))?;
+    Ok(index)
+}
+
+fn drop_embedding_index(conn: &Connection, index: &EmbeddingIndex) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM embedding_indexes WHERE name = ?",
+        [&index.name],
+    )?;
+    conn.execute_batch(&format!("DROP TABLE {}", index.name))
+}
+
+/// Makes sure that new chunks of the collection are embedded into an index
+/// for `model`, whose embeddings have `dimension` dimensions. If the active
+/// index comes from another model, it keeps serving searches while an index
+/// for `model` is built, see [`building_index`].
+pub fn sync_embedding_index(
+    conn: &Connection,
+    collection_id: i64,
+    model: &str,
+    dimension: usize,
+) -> anyhow::Result<()> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let indexes: Vec<_> = collection_indexes(&tx, collection_id)?.collect();
+    let active = indexes.iter().find(|i| i.active);
+    let fits = |i: &EmbeddingIndex| i.model.as_deref() == Some(model) && i.dimension == dimension;
+    let up_to_date = match active {
+        // Carried over embeddings can only come from the configured model if
+        // their dimension fits
+        Some(active) if active.model.is_none() && active.dimension == dimension => {
+            tx.execute(
+                "UPDATE embedding_indexes SET model = ? WHERE name = ?",
+                params![model, active.name],
+            )?;
+            true
+        }
+        Some(active) => fits(active),
+        None => {
+            create_embedding_index(&tx, collection_id, Some(model), dimension, true)?;
+            true
+        }
+    };
+    // Drop indexes built for a model that is no longer configured
+    let mut building = false;
+    for index in indexes.iter().filter(|i| !i.active) {
+        if up_to_date || !fits(index) {
+            drop_embedding_index(&tx, index)?;
+            if let Some(active) = active.filter(|_| up_to_date) {
+                reindex_unembedded(&tx, active)?;
+            }
+        } else {
+            building = true;
+        }
+    }
+    if !up_to_date && !building {
+        create_embedding_index(&tx, collection_id, Some(model), dimension, false)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Makes the fully built `index` the one searches use, dropping the index it
+/// replaces.
+pub fn activate_embedding_index(conn: &Connection, index: &EmbeddingIndex) -> anyhow::Result<()> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    if let Some(active) = active_index(&tx, index.collection_id)? {
+        drop_embedding_index(&tx, &active)?;
+    }
+    tx.execute(
+        "UPDATE embedding_indexes SET status = 'active' WHERE name = ?",
+        [&index.name],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// The tables holding indexed chunks: `documents`, every embedding index,
+/// and `member_hashes`, which goes along with them.
+fn chunk_tables(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut tables = vec!["documents".to_string(), "member_hashes".to_string()];
+    tables.extend(embedding_indexes(conn)?.into_iter().map(|i| i.name));
+    Ok(tables)
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Root {
+    pub id: i64,
+    pub path: String,
+    pub collection: String,
+    pub files: u64,
+    pub done: u64,
+}
+
+/// Records `path` as a root of `collection`, creating the collection if
+/// needed, and queues it for scanning. Adding a root that is already known
+/// moves it to `collection`. Files that end up in another collection than the
+/// one they were indexed into are indexed again. Without a collection, the
+/// root stays in the collection it is already in, see [`collection_of`].
+pub fn add_root(conn: &Connection, path: &Path, collection: Option<&str>) -> anyhow::Result<()> {
+    let Some(path) = path.to_str() else {
+        anyhow::bail!("root {} is not valid UTF-8", path.display());
+    };
+    let tx = conn.unchecked_transaction()?;
+    let previous = collection_of(&tx, path)?;
+    let collection_id = match collection {
+        Some(name) => ensure_collection(&tx, name)?,
+        None => previous.id,
+    };
+    tx.execute(
+        r#"
+        INSERT INTO roots (path, collection_id) VALUES (?1, ?2)
+        ON CONFLICT(path) DO UPDATE SET collection_id = excluded.collection_id;
+        "#,
+        params![path, collection_id],
+    )?;
+    if previous.id != collection_id {
+        reindex_path(&tx, path)?;
+    }
+    tx.execute(
+        "INSERT OR IGNORE INTO dir_queue (path) VALUES (?);",
+        params![path],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn list_roots(conn: &Connection) -> anyhow::Result<Vec<Root>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT r.id, r.path, c.name, COUNT(f.id), COUNT(f.id) FILTER (WHERE f.status = 'done')
+        FROM roots r
+        JOIN collections c ON c.id = r.collection_id
+        LEFT JOIN file_queue f ON substr(f.path, 1, length(r.path) + 1) = r.path || '/'
+        GROUP BY r.id
+        ORDER BY r.path;
+        "#,
+    )?;
+    let roots = stmt
+        .query_map([], |row| {
+            Ok(Root {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                collection: row.get(2)?,
+                files: row.get::<_, i64>(3)? as u64,
+                done: row.get::<_, i64>(4)? as u64,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(roots)
+}
+
+/// Queues every known directory for another crawl, so that new and modified
+/// files are picked up.
+pub fn rescan(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute(
+        r#"
+        UPDATE dir_queue
+        SET status = 'pending', attempts = 0, next_attempt_at = NULL, updated_at = CURRENT_TIMESTAMP
+        WHERE status != 'scanning'
+        "#,
+        [],
+    )?;
+    Ok(())
+}
+
+/// Hands rows that have been `scanning` for longer than `lease_timeout`
+/// seconds back to the scanners. Such rows were claimed by a process that died
+/// before finishing them.
+pub fn reclaim_stale_leases(conn: &Connection, lease_timeout: u64) -> anyhow::Result<()> {
+    for table in ["dir_queue", "file_queue"] {
+        conn.execute(
+            &format!(
+                "UPDATE {table} SET status = 'pending', leased_at = NULL
+                WHERE status = 'scanning' AND (leased_at IS NULL OR leased_at < unixepoch() - ?1)"
+            ),
+            params![lease_timeout as i64],
+        )?;
+    }
+    Ok(())
+}
+
+/// Matches the indexed chunks of the file at `?1`, and of its members if it
+/// is an archive.
+const CHUNKS_OF: &str = "file_path = ?1 OR substr(file_path, 1, length(?1) + 2) = ?1 || '!/'";
+
+/// Removes the indexed chunks of the file at `path`.
+pub fn delete_chunks(conn: &Connection, path: &str) -> anyhow::Result<()> {
+    delete_chunks_from(conn, &chunk_tables(conn)?, path, &[])
+}
+
+/// Removes the indexed chunks of the file at `path`, in the collection
+/// `collection_id`, before it is indexed again, except those of the archive
+/// members at the virtual paths in `kept`. While an index is built for a new
+/// embedding model, the active index keeps the file's previous chunks, so that
+/// searches still find the file until the new index takes over.
+pub fn clear_chunks(
+    conn: &Connection,
+    path: &str,
+    collection_id: i64,
+    kept: &[String],
+) -> anyhow::Result<()> {
+    let mut tables = chunk_tables(conn)?;
+    if building_index(conn, collection_id)?.is_some() {
+        if let Some(active) = active_index(conn, collection_id)? {
+            tables.retain(|t| *t != active.name);
+        }
+    }
+    delete_chunks_from(conn, &tables, path, kept)
+}
+
+fn delete_chunks_from(
+    conn: &Connection,
+    tables: &[String],
+    path: &str,
+    kept: &[String],
+) -> anyhow::Result<()> {
+    let kept = serde_json::to_string(kept)?;
+    for table in tables {
+        conn.execute(
+            &format!(
+                "DELETE FROM {table}
+                WHERE ({CHUNKS_OF}) AND file_path NOT IN (SELECT value FROM json_each(?2))"
+            ),
+            params![path, kept],
+        )?;
+    }
+    Ok(())
+}
+
+/// The hash of the archive member at the virtual path `path` when it was
+/// indexed, if it is indexed.
+pub fn member_hash(conn: &Connection, path: &str) -> rusqlite::Result<Option<String>> {
+    conn.query_row(
+        "SELECT hash FROM member_hashes WHERE file_path = ?",
+        [path],
+        |r| r.get(0),
+    )
+    .optional()
+}
+
+/// Re-keys the indexed chunks of the file at `from` to `to`, replacing any
+/// chunks `to` already had.
+pub fn move_chunks(conn: &Connection, from: &str, to: &str) -> anyhow::Result<()> {
+    delete_chunks(conn, to)?;
+    for table in chunk_tables(conn)? {
+        conn.execute(
+            &format!(
+                "UPDATE {table} SET file_path = ?2 || substr(file_path, length(?1) + 1)
+                WHERE {CHUNKS_OF}"
+            ),
+            params![from, to],
+        )?;
+    }
+    Ok(())
+}
+
+/// Forgets the root `path` along with everything queued or indexed below it,
+/// unless it is nested in another root. Files nested in a root of another
+/// collection are indexed again into that one. Roots nested in it are left as
+/// they are.
+pub fn remove_root(conn: &Connection, path: &str) -> anyhow::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let collection = collection_of(&tx, path)?;
+    tx.execute("DELETE FROM roots WHERE path = ?1", params![path])?;
+    let nested: bool = tx.query_row(
+        "SELECT EXISTS (SELECT 1 FROM roots WHERE substr(?1, 1, length(path) + 1) = path || '/')",
+        params![path],
+        |r| r.get(0),
+    )?;
+    if nested {
+        if collection_of(&tx, path)? != collection {
+            reindex_path(&tx, path)?;
+        }
+        tx.commit()?;
+        return Ok(());
+    }
+    for (table, column) in path_columns(&tx)? {
+        tx.execute(
+            &format!(
+                "DELETE FROM {table} WHERE {} AND NOT {}",
+                below(column),
+                in_nested_root(&format!("{table}.{column}"))
+            ),
+            params![path],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Matches the values of `column` that are the path `?1`, or below it if it is
+/// a directory or an archive.
+fn below(column: &str) -> String {
+    format!(
+        "({column} = ?1
+            OR substr({column}, 1, length(?1) + 1) = ?1 || '/'
+            OR substr({column}, 1, length(?1) + 2) = ?1 || '!/')"
+    )
+}
+
+/// Matches the values of `column` in a root nested below the path `?1`. The
+/// column needs its table, as `roots` has a `path` column too.
+fn in_nested_root(column: &str) -> String {
+    format!(
+        "EXISTS (
+            SELECT 1 FROM roots r
+            WHERE substr(r.path, 1, length(?1) + 1) = ?1 || '/'
+                AND ({column} = r.path OR substr({column}, 1, length(r.path) + 1) = r.path || '/')
+        )"
+    )
+}
+
+/// Deletes the queue entries and indexed chunks for `path`, and for everything
+/// below it if it is a directory or an archive.
+pub fn purge_path(conn: &Connection, path: &str) -> anyhow::Result<()> {
+    for (table, column) in path_columns(conn)? {
+        conn.execute(
+            &format!("DELETE FROM {table} WHERE {}", below(column)),
+            params![path],
+        )?;
+    }
+    Ok(())
+}
+
+/// Deletes the indexed chunks below `path` and queues its files to be indexed
+/// again, after they moved to another collection. Roots nested in it keep
+/// their own collection.
+fn reindex_path(conn: &Connection, path: &str) -> anyhow::Result<()> {
+    for table in chunk_tables(conn)? {
+        conn.execute(
+            &format!(
+                "DELETE FROM {table} WHERE {} AND NOT {}",
+                below("file_path"),
+                in_nested_root(&format!("{table}.file_path"))
+            ),
+            params![path],
+        )?;
+    }
+    conn.execute(
+        &format!(
+            "UPDATE file_queue
+            SET status = 'pending', hash = NULL, attempts = 0, next_attempt_at = NULL
+            WHERE {} AND NOT {}",
+            below("path"),
+            in_nested_root("file_queue.path")
+        ),
+        params![path],
+    )?;
+    Ok(())
+}
+
+/// Queues the files whose chunks are missing from or outdated in `index` to be
+/// indexed again. Files indexed while an index for another model was built
+/// were only embedded into that one, and the active index kept their previous
+/// chunks.
+fn reindex_unembedded(conn: &Connection, index: &EmbeddingIndex) -> anyhow::Result<()> {
+    let paths: Vec<String> = conn
+        .prepare(&format!(
+            r#"
+            SELECT DISTINCT CASE WHEN instr(file_path, '!/') > 0
+                THEN substr(file_path, 1, instr(file_path, '!/') - 1)
+                ELSE file_path END
+            FROM (
+                SELECT file_path FROM documents d
+                WHERE collection_id = ?1
+                    AND NOT EXISTS (SELECT 1 FROM {0} e WHERE e.rowid = d.rowid)
+                UNION
+                SELECT file_path FROM {0} e
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM documents d
+                    WHERE d.rowid = e.rowid AND d.file_path = e.file_path
+                        AND d.content = e.content AND d.byte_start IS e.byte_start
+                )
+            )
+            "#,
+            index.name
+        ))?
+        .query_map([index.collection_id], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    for path in paths {
+        reindex_path(conn, &path)?;
+    }
+    Ok(())
+}
+
+/// Re-keys the queue entries and indexed chunks of `from`, and of everything
+/// below it or inside it, to `to`, so that renamed files do not need to be
+/// embedded again.
+pub fn move_path(conn: &Connection, from: &str, to: &str) -> anyhow::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    // Renaming over an existing file replaces it
+    purge_path(&tx, to)?;
+    for (table, column) in path_columns(&tx)? {
+        tx.execute(
+            &format!(
+                "UPDATE {table} SET {column} = ?2 || substr({column}, length(?1) + 1) WHERE {}",
+                below(column)
+            ),
+            params![from, to],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// The tables keyed by path, along with their path column.
+fn path_columns(conn: &Connection) -> rusqlite::Result<Vec<(String, &'static str)>> {
+    let mut columns: Vec<_> = chunk_tables(conn)?
+        .into_iter()
+        .map(|table| (table, "file_path"))
+        .collect();
+    columns.push(("file_queue".into(), "path"));
+    columns.push(("dir_queue".into(), "path"));
+    Ok(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use zerocopy::IntoBytes;
+
+    use super::*;
+
+    /// A database as created by a version from before migrations, which all
+    /// left `user_version` at 0.
+    struct Unversioned {
+        /// Request that last changed the schema.
+        name: &'static str,
+        roots: bool,
+        dir_columns: &'static str,
+        file_columns: &'static str,
+        /// Columns of `documents` and `embeddings` after `content`.
+        chunk_columns: &'static [&'static str],
+        /// Whether embeddings are in tables listed in `embedding_indexes`
+        /// rather than in `embeddings`.
+        embedding_indexes: bool,
+    }
+
+    const LEASES: &str = "error TEXT, leased_at INTEGER, attempts INTEGER NOT NULL DEFAULT 0, next_attempt_at INTEGER";
+    const OFFSETS: &[&str] = &["byte_start", "byte_end", "line_start", "line_end"];
+    const TOKEN_COUNTS: &[&str] = &[
+        "byte_start",
+        "byte_end",
+        "line_start",
+        "line_end",
+        "token_count",
+    ];
+    const METADATA: &[&str] = &[
+        "byte_start",
+        "byte_end",
+        "line_start",
+        "line_end",
+        "token_count",
+        "metadata",
+    ];
+
+    const UNVERSIONED: &[Unversioned] = &[
+        Unversioned {
+            name: "baseline",
+            roots: false,
+            dir_columns: "",
+            file_columns: "error TEXT",
+            chunk_columns: &[],
+            embedding_indexes: false,
+        },
+        Unversioned {
+            name: "roots",
+            roots: true,
+            dir_columns: "",
+            file_columns: "error TEXT",
+            chunk_columns: &[],
+            embedding_indexes: false,
+        },
+        Unversioned {
+            name: "hashes",
+            roots: true,
+            dir_columns: "",
+            file_columns: "error TEXT, mtime INTEGER, size INTEGER, hash TEXT",
+            chunk_columns: &[],
+            embedding_indexes: false,
+        },
+        Unversioned {
+            name: "leases",
+            roots: true,
+            dir_columns: LEASES,
+            file_columns: "error TEXT, mtime INTEGER, size INTEGER, hash TEXT, leased_at INTEGER, attempts INTEGER NOT NULL DEFAULT 0, next_attempt_at INTEGER",
+            chunk_columns: &[],
+            embedding_indexes: false,
+        },
+        Unversioned {
+            name: "offsets",
+            roots: true,
+            dir_columns: LEASES,
+            file_columns: "error TEXT, mtime INTEGER, size INTEGER, hash TEXT, leased_at INTEGER, attempts INTEGER NOT NULL DEFAULT 0, next_attempt_at INTEGER",
+            chunk_columns: OFFSETS,
+            embedding_indexes: false,
+        },
+        Unversioned {
+            name: "token counts",
+            roots: true,
+            dir_columns: LEASES,
+            file_columns: "error TEXT, mtime INTEGER, size INTEGER, hash TEXT, leased_at INTEGER, attempts INTEGER NOT NULL DEFAULT 0, next_attempt_at INTEGER",
+            chunk_columns: TOKEN_COUNTS,
+            embedding_indexes: false,
+        },
+        Unversioned {
+            name: "metadata",
+            roots: true,
+            dir_columns: LEASES,
+            file_columns: "error TEXT, mtime INTEGER, size INTEGER, hash TEXT, leased_at INTEGER, attempts INTEGER NOT NULL DEFAULT 0, next_attempt_at INTEGER",
+            chunk_columns: METADATA,
+            embedding_indexes: false,
+        },
+        Unversioned {
+            name: "encodings",
+            roots: true,
+            dir_columns: LEASES,
+            file_columns: "error TEXT, mtime INTEGER, size INTEGER, hash TEXT, encoding TEXT, leased_at INTEGER, attempts INTEGER NOT NULL DEFAULT 0, next_attempt_at INTEGER",
+            chunk_columns: METADATA,
+            embedding_indexes: false,
+        },
+        Unversioned {
+            name: "mime types",
+            roots: true,
+            dir_columns: LEASES,
+            file_columns: "error TEXT, mtime INTEGER, size INTEGER, hash TEXT, encoding TEXT, mime TEXT, leased_at INTEGER, attempts INTEGER NOT NULL DEFAULT 0, next_attempt_at INTEGER",
+            chunk_columns: METADATA,
+            embedding_indexes: false,
+        },
+        Unversioned {
+            name: "embedding indexes",
+            roots: true,
+            dir_columns: LEASES,
+            file_columns: "error TEXT, mtime INTEGER, size INTEGER, hash TEXT, encoding TEXT, mime TEXT, leased_at INTEGER, attempts INTEGER NOT NULL DEFAULT 0, next_attempt_at INTEGER",
+            chunk_columns: METADATA,
+            embedding_indexes: true,
+        },
+    ];
+
+    impl Unversioned {
+        fn schema(&self) -> String {
+            let mut sql = String::new();
+            if self.roots {
+                sql += "CREATE TABLE roots (id INTEGER PRIMARY KEY AUTOINCREMENT, path TEXT UNIQUE NOT NULL, added_at DATETIME DEFAULT CURRENT_TIMESTAMP);\n";
+            }
+            let queue = |table: &str, extra: &str, columns: &str| {
+                let columns = [
+                    "id INTEGER PRIMARY KEY AUTOINCREMENT",
+                    "path TEXT UNIQUE NOT NULL",
+                    extra,
+                    "status TEXT CHECK(status IN ('pending', 'scanning', 'done', 'error')) DEFAULT 'pending'",
+                    "updated_at DATETIME DEFAULT CURRENT_TIMESTAMP",
+                    columns,
+                ];
+                let columns: Vec<&str> = columns.into_iter().filter(|c| !c.is_empty()).collect();
+                format!("CREATE TABLE {table} ({});\n", columns.join(", "))
+            };
+            sql += &queue("dir_queue", "", self.dir_columns);
+            sql += &queue(
+                "file_queue",
+                "dir_id INTEGER REFERENCES dir_queue(id) ON DELETE CASCADE",
+                self.file_columns,
+            );
+            let fts: Vec<String> = self
+                .chunk_columns
+                .iter()
+                .map(|c| format!("{c} UNINDEXED"))
+                .collect();
+            let vec: Vec<String> = self
+                .chunk_columns
+                .iter()
+                .map(|&c| match c {
+                    "metadata" => "+metadata TEXT".to_string(),
+                    c => format!("+{c} INTEGER"),
+                })
+                .collect();
+            sql += &format!(
+                "CREATE VIRTUAL TABLE documents USING fts5(file_path UNINDEXED, chunk_index UNINDEXED, content{});\n",
+                fts.iter().map(|c| format!(", {c}")).collect::<String>()
+            );
+            let embeddings = if self.embedding_indexes {
+                sql += "CREATE TABLE embedding_indexes (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT UNIQUE NOT NULL, model TEXT, dimension INTEGER NOT NULL, status TEXT CHECK(status IN ('building', 'active')) NOT NULL, created_at DATETIME DEFAULT CURRENT_TIMESTAMP);\n";
+                sql += "INSERT INTO embedding_indexes (name, model, dimension, status) VALUES ('embeddings_1', '/models/a.gguf', 384, 'active');\n";
+                "embeddings_1"
+            } else {
+                "embeddings"
+            };
+            sql += &format!(
+                "CREATE VIRTUAL TABLE {embeddings} USING vec0(file_path TEXT, chunk_index INTEGER, content TEXT, {}embedding float[384]);\n",
+                vec.iter().map(|c| format!("{c}, ")).collect::<String>()
+            );
+            sql
+        }
+
+        /// Whether its chunks can be kept.
+        fn current_chunks(&self) -> bool {
+            self.chunk_columns.contains(&"metadata")
+        }
+
+        fn embeddings_table(&self) -> &'static str {
+            if self.embedding_indexes {
+                "embeddings_1"
+            } else {
+                "embeddings"
+            }
+        }
+    }
+
+    fn open() -> Connection {
+        load_sqlite_vec();
+        Connection::open_in_memory().unwrap()
+    }
+
+    /// Creates a database like `fixture` would have, holding a done file
+    /// with two chunks. A deleted chunk makes the rowids of `embeddings` drift
+    /// apart from those of `documents`.
+    fn create(fixture: &Unversioned) -> Connection {
+        let conn = open();
+        conn.execute_batch(&fixture.schema()).unwrap();
+        if fixture.roots {
+            conn.execute("INSERT INTO roots (path) VALUES ('/r')", [])
+                .unwrap();
+        }
+        conn.execute(
+            "INSERT INTO dir_queue (path, status) VALUES ('/r', 'done')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO file_queue (path, dir_id, status) VALUES ('/r/a.txt', 1, 'done')",
+            [],
+        )
+        .unwrap();
+        if fixture.file_columns.contains("hash") {
+            conn.execute("UPDATE file_queue SET hash = 'h'", [])
+                .unwrap();
+        }
+        let embedding = vec![0.5f32; LEGACY_DIMENSION];
+        for (chunk_index, content) in [(0, "deleted"), (0, "kept"), (1, "also kept")] {
+            conn.execute(
+                "INSERT INTO documents (file_path, chunk_index, content) VALUES ('/r/a.txt', ?, ?)",
+                params![chunk_index, content],
+            )
+            .unwrap();
+            let rowid = fixture.embedding_indexes.then(|| conn.last_insert_rowid());
+            conn.execute(
+                &format!(
+                    "INSERT INTO {} (rowid, file_path, chunk_index, content, embedding) VALUES (?, '/r/a.txt', ?, ?, ?)",
+                    fixture.embeddings_table()
+                ),
+                params![rowid, chunk_index, content, embedding.as_bytes()],
+            )
+            .unwrap();
+            if content == "deleted" {
+                conn.execute("DELETE FROM documents", []).unwrap();
+                conn.execute(&format!("DELETE FROM {}", fixture.embeddings_table()), [])
+                    .unwrap();
+            }
+        }
+        conn
+    }
+
+    /// The tables of the database, with the name, type, constraints and
+    /// default of each column, in no particular order.
+    fn schema_of(conn: &Connection) -> Vec<(String, Vec<String>)> {
+        let tables: Vec<String> = conn
+            .prepare(
+                "SELECT name FROM sqlite_master
+                WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND name NOT LIKE 'documents_%'
+                    AND name NOT LIKE 'embeddings_%'
+                ORDER BY name",
+            )
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        tables
+            .into_iter()
+            .map(|table| {
+                let mut columns: Vec<String> = conn
+                    .prepare(
+                        "SELECT name, type, \"notnull\", dflt_value, pk FROM pragma_table_info(?)",
+                    )
+                    .unwrap()
+                    .query_map([&table], |r| {
+                        Ok(format!(
+                            "{} {} {} {:?} {}",
+                            r.get::<_, String>(0)?,
+                            r.get::<_, String>(1)?,
+                            r.get::<_, bool>(2)?,
+                            r.get::<_, Option<String>>(3)?,
+                            r.get::<_, i64>(4)?
+                        ))
+                    })
+                    .unwrap()
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                columns.sort();
+                (table, columns)
+            })
+            .collect()
+    }
+
+    fn version(conn: &Connection) -> usize {
+        conn.query_row("PRAGMA user_version", [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn creates_the_latest_schema() {
+        let conn = open();
+        migrate(&conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len());
+        assert!(embedding_indexes(&conn).unwrap().is_empty());
+        let collections = list_collections(&conn).unwrap();
+        assert_eq!(collections.len(), 1);
+        assert_eq!(collections[0].name, DEFAULT_COLLECTION);
+        // Migrating again changes nothing
+        let schema = schema_of(&conn);
+        migrate(&conn).unwrap();
+        assert_eq!(schema_of(&conn), schema);
+    }
+
+    #[test]
+    fn upgrades_unversioned_databases() {
+        let latest = open();
+        migrate(&latest).unwrap();
+        for fixture in UNVERSIONED {
+            let conn = create(fixture);
+            migrate(&conn).unwrap_or_else(|e| panic!("{}: {e:#}", fixture.name));
+            assert_eq!(version(&conn), MIGRATIONS.len(), "{}", fixture.name);
+            assert_eq!(schema_of(&conn), schema_of(&latest), "{}", fixture.name);
+
+            let (status, hash): (String, Option<String>) = conn
+                .query_row(
+                    "SELECT status, hash FROM file_queue WHERE path = '/r/a.txt'",
+                    [],
+                    |r| Ok((r.get(0)?, r.get(1)?)),
+                )
+                .unwrap();
+            let chunks: Vec<String> = conn
+                .prepare("SELECT content FROM documents ORDER BY chunk_index")
+                .unwrap()
+                .query_map([], |r| r.get(0))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let indexes = embedding_indexes(&conn).unwrap();
+            if fixture.current_chunks() {
+                assert_eq!(chunks, ["kept", "also kept"], "{}", fixture.name);
+                assert_eq!(status, "done", "{}", fixture.name);
+                assert_eq!(hash.as_deref(), Some("h"), "{}", fixture.name);
+                assert_eq!(indexes.len(), 1, "{}", fixture.name);
+                assert!(indexes[0].active, "{}", fixture.name);
+                assert_eq!(indexes[0].dimension, LEGACY_DIMENSION, "{}", fixture.name);
+                // Everything was indexed into the default collection
+                let default = collection_of(&conn, "/r/a.txt").unwrap();
+                assert_eq!(default.name, DEFAULT_COLLECTION, "{}", fixture.name);
+                assert_eq!(indexes[0].collection_id, default.id, "{}", fixture.name);
+                let in_default: i64 = conn
+                    .query_row(
+                        "SELECT COUNT(*) FROM documents WHERE collection_id = ?",
+                        [default.id],
+                        |r| r.get(0),
+                    )
+                    .unwrap();
+                assert_eq!(in_default, 2, "{}", fixture.name);
+                // Embeddings share the rowid of their chunk
+                let embedded: i64 = conn
+                    .query_row(
+                        &format!(
+                            "SELECT COUNT(*) FROM {} e JOIN documents d ON d.rowid = e.rowid
+                            WHERE e.content = d.content",
+                            indexes[0].name
+                        ),
+                        [],
+                        |r| r.get(0),
+                    )
+                    .unwrap();
+                assert_eq!(embedded, 2, "{}", fixture.name);
+            } else {
+                assert!(chunks.is_empty(), "{}", fixture.name);
+                assert_eq!(status, "pending", "{}", fixture.name);
+                assert_eq!(hash, None, "{}", fixture.name);
+                assert!(indexes.is_empty(), "{}", fixture.name);
+            }
+        }
+    }
+
+    #[test]
+    fn upgrades_from_every_version() {
+        let latest = open();
+        migrate(&latest).unwrap();
+        for version in 0..=MIGRATIONS.len() {
+            let conn = open();
+            for migration in &MIGRATIONS[..version] {
+                migration(&conn).unwrap();
+            }
+            conn.pragma_update(None, "user_version", version).unwrap();
+            migrate(&conn).unwrap();
+            assert_eq!(schema_of(&conn), schema_of(&latest), "version {version}");
+        }
+    }
+
+    fn paths(conn: &Connection, query: &str) -> Vec<String> {
+        conn.prepare(query)
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn moves_roots_between_collections() {
+        let conn = open();
+        migrate(&conn).unwrap();
+        add_root(&conn, Path::new("/r"), None).unwrap();
+        add_root(&conn, Path::new("/r/code"), Some(DEFAULT_COLLECTION)).unwrap();
+        for path in ["/r/a.txt", "/r/code/b.rs"] {
+            conn.execute(
+                "INSERT INTO file_queue (path, status, hash) VALUES (?, 'done', 'h')",
+                [path],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO documents (file_path, chunk_index, content, collection_id)
+                VALUES (?, 0, 'chunk', 1)",
+                [path],
+            )
+            .unwrap();
+        }
+        let pending = "SELECT path FROM file_queue WHERE status = 'pending' AND hash IS NULL";
+
+        // The nested root's files are indexed again into its new collection
+        add_root(&conn, Path::new("/r/code"), Some("code")).unwrap();
+        assert_eq!(collection_of(&conn, "/r/code/b.rs").unwrap().name, "code");
+        assert_eq!(
+            collection_of(&conn, "/r/a.txt").unwrap().name,
+            DEFAULT_COLLECTION
+        );
+        assert_eq!(paths(&conn, pending), ["/r/code/b.rs"]);
+        assert_eq!(
+            paths(&conn, "SELECT file_path FROM documents"),
+            ["/r/a.txt"]
+        );
+
+        // Adding it again without a collection keeps it in its own
+        conn.execute("UPDATE file_queue SET status = 'done', hash = 'h'", [])
+            .unwrap();
+        add_root(&conn, Path::new("/r/code"), None).unwrap();
+        assert_eq!(collection_of(&conn, "/r/code/b.rs").unwrap().name, "code");
+        assert!(paths(&conn, pending).is_empty());
+        add_root(&conn, Path::new("/r/code/src"), None).unwrap();
+        assert_eq!(
+            collection_of(&conn, "/r/code/src/c.rs").unwrap().name,
+            "code"
+        );
+
+        // Once it is removed, they are back in the collection of the root
+        // around it
+        remove_root(&conn, "/r/code/src").unwrap();
+        remove_root(&conn, "/r/code").unwrap();
+        assert_eq!(
+            collection_of(&conn, "/r/code/b.rs").unwrap().name,
+            DEFAULT_COLLECTION
+        );
+        assert_eq!(paths(&conn, pending), ["/r/code/b.rs"]);
+    }
+
+    #[test]
+    fn keeps_nested_roots() {
+        let conn = open();
+        migrate(&conn).unwrap();
+        sync_embedding_index(&conn, 1, "/models/a.gguf", 2).unwrap();
+        add_root(&conn, Path::new("/r"), None).unwrap();
+        add_root(&conn, Path::new("/r/a"), Some("a")).unwrap();
+        add_root(&conn, Path::new("/r/a/b"), Some("b")).unwrap();
+        for path in ["/r/x.txt", "/r/a/y.txt", "/r/a/b/z.txt"] {
+            conn.execute(
+                "INSERT INTO file_queue (path, status, hash) VALUES (?, 'done', 'h')",
+                [path],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO documents (file_path, chunk_index, content) VALUES (?, 0, 'chunk')",
+                [path],
+            )
+            .unwrap();
+        }
+        let pending = "SELECT path FROM file_queue WHERE status = 'pending' ORDER BY path";
+
+        // Moving a root leaves the roots nested in it in their collection
+        add_root(&conn, Path::new("/r/a"), Some("c")).unwrap();
+        assert_eq!(paths(&conn, pending), ["/r/a/y.txt"]);
+        assert_eq!(
+            paths(&conn, "SELECT file_path FROM documents ORDER BY file_path"),
+            ["/r/a/b/z.txt", "/r/x.txt"]
+        );
+
+        remove_root(&conn, "/r").unwrap();
+        assert_eq!(
+            paths(&conn, "SELECT path FROM file_queue ORDER BY path"),
+            ["/r/a/b/z.txt", "/r/a/y.txt"]
+        );
+        assert_eq!(
+            paths(&conn, "SELECT path FROM dir_queue ORDER BY path"),
+            ["/r/a", "/r/a/b"]
+        );
+        assert_eq!(
+            paths(&conn, "SELECT file_path FROM documents"),
+            ["/r/a/b/z.txt"]
+        );
+        assert_eq!(collection_of(&conn, "/r/a/b/z.txt").unwrap().name, "b");
+    }
+
+    #[test]
+    fn keeps_the_chunks_of_unchanged_members() {
+        let conn = open();
+        migrate(&conn).unwrap();
+        sync_embedding_index(&conn, 1, "/models/a.gguf", 2).unwrap();
+        let index = active_index(&conn, 1).unwrap().unwrap();
+        for path in ["/r/a.mbox!/1", "/r/a.mbox!/2", "/r/a.mbox!/3", "/r/b.txt"] {
+            conn.execute(
+                "INSERT INTO documents (file_path, chunk_index, content) VALUES (?, 0, 'chunk')",
+                [path],
+            )
+            .unwrap();
+            conn.execute(
+                &format!(
+                    "INSERT INTO {} (rowid, file_path, chunk_index, content, embedding)
+                    VALUES (?, ?, 0, 'chunk', ?)",
+                    index.name
+                ),
+                params![conn.last_insert_rowid(), path, [0.5f32; 2].as_bytes()],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO member_hashes (file_path, hash) VALUES (?, 'h')",
+                [path],
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            member_hash(&conn, "/r/a.mbox!/1").unwrap().as_deref(),
+            Some("h")
+        );
+
+        let kept = ["/r/a.mbox!/1".to_string(), "/r/a.mbox!/3".to_string()];
+        clear_chunks(&conn, "/r/a.mbox", 1, &kept).unwrap();
+        let expected = ["/r/a.mbox!/1", "/r/a.mbox!/3", "/r/b.txt"];
+        for table in ["documents", "member_hashes", &index.name] {
+            let query = format!("SELECT file_path FROM {table} ORDER BY file_path");
+            assert_eq!(paths(&conn, &query), expected, "{table}");
+        }
+
+        // Hashes follow their members around
+        move_chunks(&conn, "/r/a.mbox", "/r/b.mbox").unwrap();
+        assert_eq!(member_hash(&conn, "/r/a.mbox!/1").unwrap(), None);
+        assert_eq!(
+            member_hash(&conn, "/r/b.mbox!/1").unwrap().as_deref(),
+            Some("h")
+        );
+        delete_chunks(&conn, "/r/b.mbox").unwrap();
+        assert_eq!(
+            paths(&conn, "SELECT file_path FROM member_hashes"),
+            ["/r/b.txt"]
+        );
+    }
+
+    #[test]
+    fn keeps_searching_files_indexed_again_during_a_rebuild() {
+        let conn = open();
+        migrate(&conn).unwrap();
+        sync_embedding_index(&conn, 1, "/models/a.gguf", 2).unwrap();
+        let active = active_index(&conn, 1).unwrap().unwrap();
+        conn.execute(
+            "INSERT INTO documents (file_path, chunk_index, content) VALUES ('/r/a.txt', 0, 'chunk')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (rowid, file_path, chunk_index, content, embedding)
+                VALUES (?, '/r/a.txt', 0, 'chunk', ?)",
+                active.name
+            ),
+            params![conn.last_insert_rowid(), [0.5f32; 2].as_bytes()],
+        )
+        .unwrap();
+
+        sync_embedding_index(&conn, 1, "/models/b.gguf", 3).unwrap();
+        assert!(building_index(&conn, 1).unwrap().is_some());
+        clear_chunks(&conn, "/r/a.txt", 1, &[]).unwrap();
+        assert!(paths(&conn, "SELECT file_path FROM documents").is_empty());
+        let query = format!("SELECT file_path FROM {}", active.name);
+        assert_eq!(paths(&conn, &query), ["/r/a.txt"]);
+
+        // Going back to the previous model embeds the file into it again
+        conn.execute(
+            "INSERT INTO documents (file_path, chunk_index, content) VALUES ('/r/a.txt', 0, 'edited')",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO file_queue (path, status, hash) VALUES ('/r/a.txt', 'done', 'h')",
+            [],
+        )
+        .unwrap();
+        sync_embedding_index(&conn, 1, "/models/a.gguf", 2).unwrap();
+        assert!(building_index(&conn, 1).unwrap().is_none());
+        assert!(paths(&conn, &query).is_empty());
+        assert!(paths(&conn, "SELECT file_path FROM documents").is_empty());
+        let pending = "SELECT path FROM file_queue WHERE status = 'pending' AND hash IS NULL";
+        assert_eq!(paths(&conn, pending), ["/r/a.txt"]);
+    }
+
+    #[test]
+    fn refuses_newer_databases() {
+        let conn = open();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(migrate(&conn).is_err());
+    }
+}
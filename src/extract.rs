@@ -0,0 +1,530 @@
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use chardetng::EncodingDetector;
+use encoding_rs::{CoderResult, Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use infer::MatcherType;
+use serde_json::json;
+use zip::ZipArchive;
+
+use crate::{
+    chunker::{Chunk, Format, Metadata, Region},
+    html, mail, notebook, office,
+};
+
+/// Text extracted from a file, ready for chunking.
+#[derive(Default)]
+pub struct Document {
+    pub text: String,
+    /// Parts of `text` chunked separately.
+    pub regions: Vec<Region>,
+    /// Character encoding the file was decoded from, for text files.
+    pub encoding: Option<&'static str>,
+    /// How `text` was decoded, if it is the whole content of a plain text
+    /// file rather than text extracted from another format.
+    pub decoding: Option<Decoding>,
+}
+
+/// How a text file was decoded to UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decoding {
+    pub encoding: &'static Encoding,
+    /// Length of the byte order mark the text starts after.
+    pub bom_length: usize,
+}
+
+impl Document {
+    /// Appends `text` as a region of its own, separated from the previous one
+    /// by a blank line.
+    pub fn push(&mut self, text: &str, format: Format, metadata: Metadata) {
+        if !self.text.is_empty() {
+            self.text.push_str("\n\n");
+        }
+        let start = self.text.len();
+        self.text.push_str(text);
+        self.regions.push(Region {
+            start,
+            end: self.text.len(),
+            format,
+            metadata,
+        });
+    }
+
+    /// Maps the byte offsets of `chunks` from offsets into `text` to offsets
+    /// into the file, when `text` was decoded from a plain text file. Chunks of
+    /// text extracted from other formats keep offsets into the extracted text.
+    pub fn map_offsets(&self, chunks: &mut [Chunk]) {
+        let Some(decoding) = self.decoding else {
+            return;
+        };
+        let mut offsets: Vec<usize> = chunks
+            .iter()
+            .flat_map(|c| [c.byte_start, c.byte_end])
+            .collect();
+        offsets.sort_unstable();
+        offsets.dedup();
+        let mapped = source_offsets(&self.text, decoding, &offsets);
+        let map = |offset| mapped[offsets.binary_search(&offset).unwrap()];
+        for chunk in chunks {
+            chunk.byte_start = map(chunk.byte_start);
+            chunk.byte_end = map(chunk.byte_end);
+        }
+    }
+
+    /// Appends the regions of `other`, adding `metadata` to theirs.
+    pub fn append(&mut self, other: Document, metadata: &Metadata) {
+        for region in other.regions {
+            let mut region_metadata = metadata.clone();
+            region_metadata.extend(region.metadata);
+            self.push(
+                &other.text[region.start..region.end],
+                region.format,
+                region_metadata,
+            );
+        }
+    }
+}
+
+/// How the text of a file is extracted, decided by [`detect`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    Pdf,
+    Epub,
+    Docx,
+    Odt,
+    Spreadsheet,
+    Html,
+    /// Jupyter notebooks, JSON holding markdown and code cells.
+    Notebook,
+    /// Delimited tables, with the delimiter.
+    Csv(u8),
+    Text,
+    /// A single email message, e.g. in a Maildir.
+    Email,
+    /// Archives, whose members are indexed by [`crate::archive`]. Mailboxes
+    /// are unpacked like archives, one member per message.
+    Zip,
+    Tar,
+    Gzip,
+    Mbox,
+    /// Binary content there is no text to extract from.
+    Unsupported,
+}
+
+impl Kind {
+    pub fn is_archive(self) -> bool {
+        matches!(self, Kind::Zip | Kind::Tar | Kind::Gzip | Kind::Mbox)
+    }
+}
+
+/// The detected type of a file.
+#[derive(Clone, Copy, Debug)]
+pub struct FileType {
+    pub kind: Kind,
+    /// MIME type of the content, recorded in the queue for reporting.
+    pub mime: &'static str,
+}
+
+/// Bytes at the start of a file that [`detect`] needs.
+pub const SAMPLE_LEN: u64 = 8192;
+
+/// Detects the type of the file at `path` from `sample`, the first
+/// [`SAMPLE_LEN`] bytes of its content. Binary formats are recognized by
+/// their magic bytes; anything else that looks like text is indexed as text,
+/// whatever its extension.
+pub fn detect(path: &str, sample: &[u8]) -> FileType {
+    // Notebooks are missing from the MIME types known to `mime_guess`
+    let guessed = if path.ends_with(".ipynb") {
+        Some("application/x-ipynb+json")
+    } else {
+        mime_guess::from_path(path).first_raw()
+    };
+    let sniffed = infer::get(sample).map(|t| (t.mime_type(), t.matcher_type()));
+    if let Some((mime, _)) = sniffed.filter(|(_, m)| *m != MatcherType::Text) {
+        // Office documents are zip or OLE containers, which only their
+        // extension tells apart when their magic bytes are not in the sample
+        if matches!(mime, "application/zip" | "application/x-ole-storage") {
+            if let Some(mime) =
+                guessed.filter(|m| !matches!(kind_of(m), Kind::Zip | Kind::Unsupported))
+            {
+                return FileType {
+                    kind: kind_of(mime),
+                    mime,
+                };
+            }
+        }
+        return FileType {
+            kind: kind_of(mime),
+            mime,
+        };
+    }
+    if !looks_like_text(sample) {
+        return FileType {
+            kind: Kind::Unsupported,
+            mime: guessed.unwrap_or("application/octet-stream"),
+        };
+    }
+    if guessed == Some("application/mbox") || mail::looks_like_mbox(sample) {
+        return FileType {
+            kind: Kind::Mbox,
+            mime: "application/mbox",
+        };
+    }
+    if guessed == Some("message/rfc822") || mail::looks_like_message(sample) {
+        return FileType {
+            kind: Kind::Email,
+            mime: "message/rfc822",
+        };
+    }
+    let sniffed = sniffed.map(|(mime, _)| mime);
+    if let Some(mime) = [guessed, sniffed]
+        .into_iter()
+        .flatten()
+        .find(|m| kind_of(m) == Kind::Html)
+    {
+        return FileType {
+            kind: Kind::Html,
+            mime,
+        };
+    }
+    if let Some(mime) = guessed.filter(|m| matches!(kind_of(m), Kind::Notebook | Kind::Csv(_))) {
+        return FileType {
+            kind: kind_of(mime),
+            mime,
+        };
+    }
+    // Extensions also map to MIME types of unrelated binary formats, e.g.
+    // `.ts` to MPEG transport streams
+    let mime = guessed
+        .filter(|m| is_textual(m))
+        .or(sniffed)
+        .unwrap_or("text/plain");
+    FileType {
+        kind: Kind::Text,
+        mime,
+    }
+}
+
+fn kind_of(mime: &str) -> Kind {
+    match mime {
+        "application/pdf" => Kind::Pdf,
+        "application/epub+zip" => Kind::Epub,
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => Kind::Docx,
+        "application/vnd.oasis.opendocument.text" => Kind::Odt,
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+        | "application/vnd.ms-excel.sheet.macroEnabled.12"
+        | "application/vnd.ms-excel.sheet.binary.macroEnabled.12"
+        | "application/vnd.ms-excel"
+        | "application/vnd.oasis.opendocument.spreadsheet" => Kind::Spreadsheet,
+        "text/html" | "application/xhtml+xml" => Kind::Html,
+        "application/x-ipynb+json" => Kind::Notebook,
+        "text/csv" => Kind::Csv(b','),
+        "text/tab-separated-values" => Kind::Csv(b'\t'),
+        "application/zip" => Kind::Zip,
+        "application/x-tar" => Kind::Tar,
+        "application/gzip" => Kind::Gzip,
+        _ => Kind::Unsupported,
+    }
+}
+
+fn is_textual(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime.starts_with("application/x-")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(
+            mime,
+            "application/json" | "application/xml" | "application/javascript"
+        )
+}
+
+/// Whether `sample` looks like text in an encoding [`decode_text`] handles:
+/// UTF-16, or no zero bytes and hardly any other control characters.
+fn looks_like_text(sample: &[u8]) -> bool {
+    if Encoding::for_bom(sample).is_some() || utf16_without_bom(sample).is_some() {
+        return true;
+    }
+    let controls = sample
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+        .count();
+    !sample.contains(&0) && controls * 100 <= sample.len()
+}
+
+/// Extracts the text of the file at `path`, whose content is `bytes` and
+/// whose type is `kind`.
+pub fn extract(path: &str, kind: Kind, bytes: Vec<u8>) -> anyhow::Result<Document> {
+    match kind {
+        Kind::Pdf => return extract_pdf(&bytes),
+        Kind::Epub => return html::extract_epub(&bytes),
+        Kind::Docx => return office::extract_docx(&bytes),
+        Kind::Odt => return office::extract_odt(&bytes),
+        Kind::Spreadsheet => return office::extract_spreadsheet(&bytes),
+        Kind::Email => return mail::extract_message(&bytes),
+        Kind::Zip | Kind::Tar | Kind::Gzip | Kind::Mbox | Kind::Unsupported => {
+            bail!("no text to extract from {path}")
+        }
+        Kind::Html | Kind::Notebook | Kind::Csv(_) | Kind::Text => {}
+    }
+    let (text, decoding) = decode_text(bytes);
+    let mut document = match kind {
+        Kind::Html => html::extract_html(&text),
+        Kind::Notebook => notebook::extract_notebook(&text)?,
+        Kind::Csv(delimiter) => office::extract_csv(&text, delimiter)?,
+        _ => Document {
+            regions: vec![Region::whole(&text, Format::from_path(Path::new(path)))],
+            text,
+            decoding: Some(decoding),
+            ..Document::default()
+        },
+    };
+    document.encoding = Some(decoding.encoding.name());
+    Ok(document)
+}
+
+/// Decodes a text file to UTF-8, detecting its encoding from its byte order
+/// mark, or failing that from its content.
+fn decode_text(bytes: Vec<u8>) -> (String, Decoding) {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(&bytes) {
+        let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_length..]);
+        let decoding = Decoding {
+            encoding,
+            bom_length,
+        };
+        return (text.into_owned(), decoding);
+    }
+    let decoding = |encoding| Decoding {
+        encoding,
+        bom_length: 0,
+    };
+    // Zero bytes are valid UTF-8, so look for UTF-16 first
+    if let Some(encoding) = utf16_without_bom(&bytes) {
+        let (text, _) = encoding.decode_without_bom_handling(&bytes);
+        return (text.into_owned(), decoding(encoding));
+    }
+    let bytes = match String::from_utf8(bytes) {
+        Ok(text) => return (text, decoding(UTF_8)),
+        Err(e) => e.into_bytes(),
+    };
+    let mut detector = EncodingDetector::new();
+    detector.feed(&bytes, true);
+    let encoding = detector.guess(None, false);
+    // Undecodable bytes become replacement characters rather than failing
+    // the whole file
+    let (text, _) = encoding.decode_without_bom_handling(&bytes);
+    (text.into_owned(), decoding(encoding))
+}
+
+/// Maps `offsets`, sorted byte offsets into `text`, to the offsets of the same
+/// positions in the file `text` was decoded from, by encoding it back.
+/// Positions after undecodable bytes are approximate, since those bytes were
+/// replaced.
+fn source_offsets(text: &str, decoding: Decoding, offsets: &[usize]) -> Vec<usize> {
+    let Decoding {
+        encoding,
+        bom_length,
+    } = decoding;
+    if encoding == UTF_8 {
+        return offsets.iter().map(|o| o + bom_length).collect();
+    }
+    // Encoders for UTF-16 produce UTF-8
+    let utf16 = encoding == UTF_16LE || encoding == UTF_16BE;
+    let mut encoder = encoding.new_encoder();
+    let mut buffer = [0; 4096];
+    let mut mapped = Vec::with_capacity(offsets.len());
+    let (mut previous, mut length) = (0, bom_length);
+    for &offset in offsets {
+        let mut segment = &text[previous..offset];
+        if utf16 {
+            length += segment.encode_utf16().count() * 2;
+        } else {
+            // The encoder keeps its state across segments, as stateful
+            // encodings such as ISO-2022-JP need
+            while !segment.is_empty() {
+                let (result, read, written, _) =
+                    encoder.encode_from_utf8(segment, &mut buffer, false);
+                length += written;
+                segment = &segment[read..];
+                if result == CoderResult::InputEmpty {
+                    break;
+                }
+            }
+        }
+        mapped.push(length);
+        previous = offset;
+    }
+    mapped
+}
+
+/// Recognizes mostly-ASCII UTF-16 text without a byte order mark from the
+/// zero bytes in every other position.
+fn utf16_without_bom(bytes: &[u8]) -> Option<&'static Encoding> {
+    let sample = &bytes[..bytes.len().min(4096) & !1];
+    if sample.is_empty() {
+        return None;
+    }
+    let pairs = sample.len() / 2;
+    let zeros = |offset: usize| {
+        sample
+            .iter()
+            .skip(offset)
+            .step_by(2)
+            .filter(|&&b| b == 0)
+            .count()
+    };
+    if zeros(1) * 10 > pairs * 9 && zeros(0) == 0 {
+        Some(UTF_16LE)
+    } else if zeros(0) * 10 > pairs * 9 && zeros(1) == 0 {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
+}
+
+/// Extracts the text of each page of a PDF, one region per page so that
+/// chunks can cite their page number.
+fn extract_pdf(bytes: &[u8]) -> anyhow::Result<Document> {
+    // The PDF parser panics on some malformed files
+    let pages =
+        match std::panic::catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(bytes)) {
+            Ok(pages) => pages.context("unable to extract text from PDF")?,
+            Err(_) => bail!("unable to extract text from PDF: parser panicked"),
+        };
+    let mut document = Document::default();
+    for (i, page) in pages.iter().enumerate() {
+        let mut metadata = Metadata::new();
+        metadata.insert("page".into(), json!(i + 1));
+        document.push(page.trim_end(), Format::Text, metadata);
+    }
+    Ok(document)
+}
+
+/// Reads the entry at `path` in a zip archive as text.
+pub(crate) fn read_zip_entry(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    path: &str,
+) -> anyhow::Result<String> {
+    let mut entry = archive
+        .by_name(path)
+        .with_context(|| format!("missing {path} in archive"))?;
+    let mut content = String::new();
+    entry
+        .read_to_string(&mut content)
+        .with_context(|| format!("unable to read {path} in archive"))?;
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::WINDOWS_1252;
+
+    use super::*;
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    fn kind(path: &str, sample: &[u8]) -> Kind {
+        detect(path, sample).kind
+    }
+
+    #[test]
+    fn detects_binary_formats_by_magic_bytes() {
+        assert_eq!(kind("notes.txt", b"%PDF-1.7\n"), Kind::Pdf);
+        assert_eq!(kind("a.bin", b"PK\x03\x04rest"), Kind::Zip);
+        assert_eq!(kind("a.docx", b"PK\x03\x04rest"), Kind::Docx);
+        assert_eq!(kind("a.xlsx", b"PK\x03\x04rest"), Kind::Spreadsheet);
+        assert_eq!(kind("a", b"\x1f\x8b\x08\x00"), Kind::Gzip);
+        assert_eq!(kind("a.txt", b"\x89PNG\r\n\x1a\n"), Kind::Unsupported);
+        assert_eq!(kind("a.txt", b"\x00\x01\x02\x03"), Kind::Unsupported);
+    }
+
+    #[test]
+    fn detects_text_whatever_its_extension() {
+        let file_type = detect("Makefile", b"all:\n\tcc main.c\n");
+        assert_eq!(file_type.kind, Kind::Text);
+        assert_eq!(file_type.mime, "text/plain");
+        assert_eq!(kind("settings.ini", b"[a]\nb = 1\n"), Kind::Text);
+        // `.ts` is also the extension of MPEG transport streams
+        let file_type = detect("main.ts", b"export const a = 1;\n");
+        assert_eq!(file_type.kind, Kind::Text);
+        assert_ne!(file_type.mime, "video/mp2t");
+        assert_eq!(kind("page", b"<!DOCTYPE html><html></html>"), Kind::Html);
+        assert_eq!(kind("a.ipynb", b"{\"cells\": []}"), Kind::Notebook);
+        assert_eq!(kind("a.csv", b"a,b\n1,2\n"), Kind::Csv(b','));
+        assert_eq!(kind("a.tsv", b"a\tb\n1\t2\n"), Kind::Csv(b'\t'));
+        assert_eq!(kind("utf16.txt", &utf16le("text")), Kind::Text);
+    }
+
+    #[test]
+    fn detects_mail() {
+        let message = b"From: a@example.com\nDate: Mon, 1 Jan 2024 00:00:00 +0000\n\nBody\n";
+        assert_eq!(kind("1234.host", message), Kind::Email);
+        let mbox = [
+            b"From a@example.com Mon Jan  1 00:00:00 2024\n",
+            &message[..],
+        ]
+        .concat();
+        assert_eq!(kind("Inbox", &mbox), Kind::Mbox);
+    }
+
+    #[test]
+    fn decodes_by_byte_order_mark() {
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend(utf16le("héllo"));
+        let (text, decoding) = decode_text(bytes);
+        assert_eq!(text, "héllo");
+        assert_eq!(decoding.encoding, UTF_16LE);
+        assert_eq!(decoding.bom_length, 2);
+
+        let (text, decoding) = decode_text(b"\xef\xbb\xbfhi".to_vec());
+        assert_eq!(text, "hi");
+        assert_eq!((decoding.encoding, decoding.bom_length), (UTF_8, 3));
+    }
+
+    #[test]
+    fn detects_utf16_without_byte_order_mark() {
+        assert_eq!(utf16_without_bom(&utf16le("plain text")), Some(UTF_16LE));
+        let be: Vec<u8> = "plain text"
+            .encode_utf16()
+            .flat_map(u16::to_be_bytes)
+            .collect();
+        assert_eq!(utf16_without_bom(&be), Some(UTF_16BE));
+        assert_eq!(utf16_without_bom(b"plain text"), None);
+        assert_eq!(utf16_without_bom(b"a"), None);
+        let (text, _) = decode_text(be);
+        assert_eq!(text, "plain text");
+    }
+
+    #[test]
+    fn detects_legacy_encodings() {
+        let (text, decoding) = decode_text(b"caf\xe9 cr\xe8me br\xfbl\xe9e".to_vec());
+        assert_eq!(text, "café crème brûlée");
+        assert_eq!(decoding.encoding, WINDOWS_1252);
+        let (text, decoding) = decode_text("café".as_bytes().to_vec());
+        assert_eq!(text, "café");
+        assert_eq!(decoding.encoding, UTF_8);
+    }
+
+    #[test]
+    fn maps_offsets_back_to_the_file() {
+        let text = "é1\nabc";
+        let offsets = [0, 4, 7];
+        let with_bom = Decoding {
+            encoding: UTF_8,
+            bom_length: 3,
+        };
+        assert_eq!(source_offsets(text, with_bom, &offsets), [3, 7, 10]);
+        let utf16 = Decoding {
+            encoding: UTF_16LE,
+            bom_length: 2,
+        };
+        assert_eq!(source_offsets(text, utf16, &offsets), [2, 8, 14]);
+        let latin1 = Decoding {
+            encoding: WINDOWS_1252,
+            bom_length: 0,
+        };
+        assert_eq!(source_offsets(text, latin1, &offsets), [0, 3, 6]);
+    }
+}
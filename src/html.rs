@@ -0,0 +1,601 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use anyhow::Context;
+use scraper::{ElementRef, Html, Node, Selector};
+use serde_json::json;
+use zip::ZipArchive;
+
+use crate::{
+    chunker::{Format, Metadata, Region},
+    extract::{read_zip_entry, Document},
+};
+
+/// Elements whose content is never part of the readable text.
+const SKIPPED_ELEMENTS: &[&str] = &[
+    "head", "script", "style", "noscript", "template", "nav", "aside", "footer", "form", "button",
+    "select", "iframe", "svg", "canvas",
+];
+
+/// ARIA roles of page furniture rather than content.
+const SKIPPED_ROLES: &[&str] = &[
+    "navigation",
+    "banner",
+    "contentinfo",
+    "complementary",
+    "search",
+];
+
+/// Elements within which a `header` introduces the content rather than being
+/// the page's banner.
+const SECTIONING_ELEMENTS: &[&str] = &["article", "section", "main"];
+
+/// Elements separated from their surroundings by a blank line.
+const PARAGRAPH_ELEMENTS: &[&str] = &[
+    "p",
+    "blockquote",
+    "pre",
+    "ul",
+    "ol",
+    "dl",
+    "table",
+    "figure",
+    "hr",
+    "address",
+];
+
+/// Elements starting on a new line.
+const LINE_ELEMENTS: &[&str] = &[
+    "div",
+    "section",
+    "article",
+    "main",
+    "header",
+    "li",
+    "dt",
+    "dd",
+    "tr",
+    "br",
+    "figcaption",
+    "caption",
+];
+
+/// Extracts the readable text of an HTML page, dropping scripts, styles,
+/// navigation and the page's header and footer. Headings are kept as Markdown headings, so that the text can be
+/// chunked along them.
+pub fn extract_html(html: &str) -> Document {
+    let (text, title) = html_to_text(html);
+    let mut region = Region::whole(&text, Format::Markdown);
+    if let Some(title) = title {
+        region.metadata.insert("title".into(), json!(title));
+    }
+    Document {
+        regions: vec![region],
+        text,
+        ..Document::default()
+    }
+}
+
+/// Extracts the chapters of an EPUB book in reading order, one region per
+/// chapter titled after the book's table of contents.
+pub fn extract_epub(bytes: &[u8]) -> anyhow::Result<Document> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).context("invalid EPUB archive")?;
+    let container = read_zip_entry(&mut archive, "META-INF/container.xml")?;
+    let container = roxmltree::Document::parse(&container).context("invalid EPUB container")?;
+    let package_path = container
+        .descendants()
+        .find(|n| n.has_tag_name("rootfile"))
+        .and_then(|n| n.attribute("full-path"))
+        .context("EPUB has no package document")?
+        .to_string();
+    let package = read_zip_entry(&mut archive, &package_path)?;
+    let package = roxmltree::Document::parse(&package).context("invalid EPUB package document")?;
+
+    // Manifest items by id, as (path in the archive, media type, properties)
+    let manifest: HashMap<&str, (String, &str, &str)> = package
+        .descendants()
+        .filter(|n| n.has_tag_name("item"))
+        .filter_map(|n| {
+            Some((
+                n.attribute("id")?,
+                (
+                    resolve_href(&package_path, n.attribute("href")?),
+                    n.attribute("media-type").unwrap_or(""),
+                    n.attribute("properties").unwrap_or(""),
+                ),
+            ))
+        })
+        .collect();
+    let spine = package
+        .descendants()
+        .find(|n| n.has_tag_name("spine"))
+        .context("EPUB has no spine")?;
+
+    // Chapter titles by path, from the EPUB 3 navigation document or the
+    // EPUB 2 NCX
+    let nav = manifest
+        .values()
+        .find(|(_, _, properties)| properties.split_whitespace().any(|p| p == "nav"));
+    let ncx = spine.attribute("toc").and_then(|id| manifest.get(id));
+    let titles = match (nav, ncx) {
+        (Some((path, _, _)), _) => nav_titles(&read_zip_entry(&mut archive, path)?, path),
+        (None, Some((path, _, _))) => ncx_titles(&read_zip_entry(&mut archive, path)?, path)?,
+        (None, None) => HashMap::new(),
+    };
+
+    let mut document = Document::default();
+    for itemref in spine.children().filter(|n| n.has_tag_name("itemref")) {
+        let Some((path, media_type, _)) =
+            itemref.attribute("idref").and_then(|id| manifest.get(id))
+        else {
+            continue;
+        };
+        if !media_type.contains("html") {
+            continue;
+        }
+        let (chapter, html_title) = html_to_text(&read_zip_entry(&mut archive, path)?);
+        if chapter.trim().is_empty() {
+            continue;
+        }
+        let mut metadata = Metadata::new();
+        if let Some(title) = titles.get(path).cloned().or(html_title) {
+            metadata.insert("chapter".into(), json!(title));
+        }
+        document.push(&chapter, Format::Markdown, metadata);
+    }
+    Ok(document)
+}
+
+/// Resolves `href`, found in the archive entry at `base`, to an entry path.
+fn resolve_href(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or("");
+    let mut parts: Vec<&str> = base.split('/').collect();
+    parts.pop();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    percent_decode(&parts.join("/"))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Reads chapter titles from an EPUB 3 navigation document at `path`. The
+/// first entry pointing into a file names it.
+fn nav_titles(html: &str, path: &str) -> HashMap<String, String> {
+    let document = Html::parse_document(html);
+    let navs = Selector::parse("nav").unwrap();
+    let links = Selector::parse("a[href]").unwrap();
+    let mut navs: Vec<ElementRef> = document.select(&navs).collect();
+    let toc: Vec<ElementRef> = navs
+        .iter()
+        .copied()
+        .filter(|n| n.attr("epub:type") == Some("toc"))
+        .collect();
+    if !toc.is_empty() {
+        navs = toc;
+    }
+    let mut titles = HashMap::new();
+    for link in navs.iter().flat_map(|n| n.select(&links)) {
+        let title = link.text().collect::<Vec<_>>().join(" ");
+        let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+        if let (Some(href), false) = (link.attr("href"), title.is_empty()) {
+            titles.entry(resolve_href(path, href)).or_insert(title);
+        }
+    }
+    titles
+}
+
+/// Reads chapter titles from an EPUB 2 NCX table of contents at `path`.
+fn ncx_titles(xml: &str, path: &str) -> anyhow::Result<HashMap<String, String>> {
+    let ncx = roxmltree::Document::parse(xml).context("invalid EPUB table of contents")?;
+    let mut titles = HashMap::new();
+    for point in ncx.descendants().filter(|n| n.has_tag_name("navPoint")) {
+        let title = point
+            .children()
+            .find(|n| n.has_tag_name("navLabel"))
+            .and_then(|l| l.descendants().find(|n| n.has_tag_name("text")))
+            .and_then(|t| t.text())
+            .map(|t| t.split_whitespace().collect::<Vec<_>>().join(" "));
+        let src = point
+            .children()
+            .find(|n| n.has_tag_name("content"))
+            .and_then(|c| c.attribute("src"));
+        if let (Some(title), Some(src)) = (title, src) {
+            if !title.is_empty() {
+                titles.entry(resolve_href(path, src)).or_insert(title);
+            }
+        }
+    }
+    Ok(titles)
+}
+
+/// Converts HTML to plain text with Markdown headings and list items,
+/// returning it along with the page title.
+fn html_to_text(html: &str) -> (String, Option<String>) {
+    let document = Html::parse_document(html);
+    let title = Selector::parse("head > title").unwrap();
+    let title = document
+        .select(&title)
+        .next()
+        .map(|t| {
+            t.text()
+                .collect::<String>()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|t| !t.is_empty());
+    let mut writer = TextWriter::default();
+    writer.element(document.root_element());
+    (writer.text, title)
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Default)]
+enum Break {
+    #[default]
+    None,
+    Space,
+    Line,
+    Paragraph,
+}
+
+#[derive(Default)]
+struct TextWriter {
+    text: String,
+    /// Separator owed before the next text.
+    pending: Break,
+    /// Depth of `pre` elements, whose whitespace is kept.
+    pre: usize,
+    /// Depth of sectioning elements.
+    sectioning: usize,
+}
+
+impl TextWriter {
+    fn element(&mut self, element: ElementRef) {
+        let el = element.value();
+        let name = el.name();
+        if SKIPPED_ELEMENTS.contains(&name)
+            || el.attr("role").is_some_and(|r| SKIPPED_ROLES.contains(&r))
+            || el.attr("hidden").is_some()
+            || el.attr("aria-hidden") == Some("true")
+            || (name == "header" && self.sectioning == 0)
+        {
+            return;
+        }
+
+        let heading = match name.as_bytes() {
+            [b'h', level @ b'1'..=b'6'] => Some((level - b'0') as usize),
+            _ => None,
+        };
+        let block = if heading.is_some() || PARAGRAPH_ELEMENTS.contains(&name) {
+            Break::Paragraph
+        } else if LINE_ELEMENTS.contains(&name) {
+            Break::Line
+        } else if matches!(name, "td" | "th") {
+            Break::Space
+        } else {
+            Break::None
+        };
+        self.separate(block);
+        if let Some(level) = heading {
+            self.write(&"#".repeat(level));
+            self.separate(Break::Space);
+        } else if name == "li" {
+            self.write("-");
+            self.separate(Break::Space);
+        }
+        if name == "pre" {
+            self.pre += 1;
+        }
+        let sectioning = SECTIONING_ELEMENTS.contains(&name);
+        if sectioning {
+            self.sectioning += 1;
+        }
+
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => self.text_node(text),
+                Node::Element(_) => {
+                    if let Some(child) = ElementRef::wrap(child) {
+                        self.element(child);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if name == "pre" {
+            self.pre -= 1;
+        }
+        if sectioning {
+            self.sectioning -= 1;
+        }
+        self.separate(block);
+    }
+
+    fn text_node(&mut self, text: &str) {
+        if self.pre > 0 {
+            self.write(text);
+            return;
+        }
+        if text.starts_with(char::is_whitespace) {
+            self.separate(Break::Space);
+        }
+        for (i, word) in text.split_whitespace().enumerate() {
+            if i > 0 {
+                self.separate(Break::Space);
+            }
+            self.write(word);
+        }
+        if text.ends_with(char::is_whitespace) {
+            self.separate(Break::Space);
+        }
+    }
+
+    /// Owes at least `separator` before the next text.
+    fn separate(&mut self, separator: Break) {
+        if separator > self.pending {
+            self.pending = separator;
+        }
+    }
+
+    fn write(&mut self, s: &str) {
+        if !self.text.is_empty() {
+            match self.pending {
+                Break::None => {}
+                Break::Space => self.text.push(' '),
+                Break::Line => self.text.push('\n'),
+                Break::Paragraph => self.text.push_str("\n\n"),
+            }
+        }
+        self.pending = Break::None;
+        self.text.push_str(s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+    use crate::chunker::chunk_text;
+
+    const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container xmlns="urn:oasis:names:tc:opendocument:xmlns:container" version="1.0">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#;
+
+    /// Builds an EPUB with the given package document and other files, named
+    /// relative to the package's directory.
+    fn epub(package: &str, files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        let mut add = |name: &str, content: &str| {
+            writer
+                .start_file(name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        };
+        add("mimetype", "application/epub+zip");
+        add("META-INF/container.xml", CONTAINER);
+        add("OEBPS/content.opf", package);
+        for (name, content) in files {
+            add(&format!("OEBPS/{name}"), content);
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// A package document listing `items` as (id, href, properties), with
+    /// the spine in the order of `spine`.
+    fn package(items: &[(&str, &str, &str)], spine: &[&str], toc: Option<&str>) -> String {
+        let manifest: String = items
+            .iter()
+            .map(|(id, href, properties)| {
+                let media_type = if href.ends_with(".ncx") {
+                    "application/x-dtbncx+xml"
+                } else {
+                    "application/xhtml+xml"
+                };
+                format!(
+                    r#"<item id="{id}" href="{href}" media-type="{media_type}" properties="{properties}"/>"#
+                )
+            })
+            .collect();
+        let spine: String = spine
+            .iter()
+            .map(|id| format!(r#"<itemref idref="{id}"/>"#))
+            .collect();
+        let toc = toc.map(|id| format!(r#" toc="{id}""#)).unwrap_or_default();
+        format!(
+            r#"<?xml version="1.0"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <manifest>{manifest}</manifest>
+  <spine{toc}>{spine}</spine>
+</package>"#
+        )
+    }
+
+    fn xhtml(body: &str) -> String {
+        format!(
+            r#"<?xml version="1.0"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>Book</title></head>
+<body>{body}</body>
+</html>"#
+        )
+    }
+
+    /// The text and chapter title of each region of `document`.
+    fn chapters(document: &Document) -> Vec<(&str, Option<&str>)> {
+        document
+            .regions
+            .iter()
+            .map(|r| {
+                (
+                    &document.text[r.start..r.end],
+                    r.metadata.get("chapter").and_then(|c| c.as_str()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drops_boilerplate() {
+        let html = r#"<!DOCTYPE html>
+<html>
+<head><title> The   Page </title><style>p { color: red }</style></head>
+<body>
+  <header><a href="/">Site name</a></header>
+  <nav><ul><li><a href="/a">Home</a></li></ul></nav>
+  <main>
+    <article>
+      <header><h1>Intro</h1></header>
+      <p>Some <b>bold</b> text.<script>track()</script></p>
+      <div role="navigation">Next</div>
+    </article>
+  </main>
+  <footer>Copyright</footer>
+  <script>init()</script>
+</body>
+</html>"#;
+        let document = extract_html(html);
+        assert_eq!(document.text, "# Intro\n\nSome bold text.");
+        assert_eq!(document.regions[0].metadata["title"], "The Page");
+    }
+
+    #[test]
+    fn keeps_headings_as_markdown_sections() {
+        let html = "<h1>Guide</h1><p>Welcome.</p>\
+                    <h2>Install</h2><ul><li>Download</li><li>Run</li></ul>\
+                    <h2>Use</h2><p>Search.</p>";
+        let document = extract_html(html);
+        assert_eq!(
+            document.text,
+            "# Guide\n\nWelcome.\n\n## Install\n\n- Download\n- Run\n\n## Use\n\nSearch."
+        );
+        let words = |s: &str| s.split_whitespace().count();
+        let chunks = chunk_text(&document.text, &document.regions, 10, 0, words);
+        let headings: Vec<_> = chunks.iter().map(|c| &c.metadata["headings"]).collect();
+        assert_eq!(
+            headings,
+            [
+                &json!(["Guide"]),
+                &json!(["Guide", "Install"]),
+                &json!(["Guide", "Use"])
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_epub_chapters_in_spine_order_titled_by_the_nav_document() {
+        let nav = xhtml(
+            r##"<nav epub:type="landmarks"><a href="text/two.xhtml">Start here</a></nav>
+<nav epub:type="toc"><ol>
+  <li><a href="text/one.xhtml">The   First</a></li>
+  <li><a href="text/two.xhtml#top">The Second</a></li>
+</ol></nav>"##,
+        );
+        let package = package(
+            &[
+                ("nav", "nav.xhtml", "nav"),
+                ("c1", "text/one.xhtml", ""),
+                ("c2", "text/two.xhtml", ""),
+                ("c3", "text/three%20more.xhtml", ""),
+            ],
+            &["c2", "c1", "c3"],
+            None,
+        );
+        let book = epub(
+            &package,
+            &[
+                ("nav.xhtml", &nav),
+                ("text/one.xhtml", &xhtml("<p>One.</p>")),
+                ("text/two.xhtml", &xhtml("<p>Two.</p>")),
+                ("text/three more.xhtml", &xhtml("<p>Three.</p>")),
+            ],
+        );
+        let document = extract_epub(&book).unwrap();
+        assert_eq!(
+            chapters(&document),
+            [
+                ("Two.", Some("The Second")),
+                ("One.", Some("The First")),
+                ("Three.", Some("Book")),
+            ]
+        );
+    }
+
+    #[test]
+    fn titles_epub_chapters_from_the_ncx() {
+        let ncx = r#"<?xml version="1.0"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <navMap>
+    <navPoint id="p1" playOrder="1">
+      <navLabel><text>Chapter I</text></navLabel>
+      <content src="one.xhtml"/>
+      <navPoint id="p2" playOrder="2">
+        <navLabel><text>Chapter I, part two</text></navLabel>
+        <content src="one.xhtml#part2"/>
+      </navPoint>
+    </navPoint>
+    <navPoint id="p3" playOrder="3">
+      <navLabel><text> Chapter
+        II </text></navLabel>
+      <content src="two.xhtml"/>
+    </navPoint>
+  </navMap>
+</ncx>"#;
+        let package = package(
+            &[
+                ("ncx", "toc.ncx", ""),
+                ("c1", "one.xhtml", ""),
+                ("c2", "two.xhtml", ""),
+            ],
+            &["c1", "c2"],
+            Some("ncx"),
+        );
+        let book = epub(
+            &package,
+            &[
+                ("toc.ncx", ncx),
+                ("one.xhtml", &xhtml("<p>One.</p>")),
+                ("two.xhtml", &xhtml("<p>Two.</p>")),
+            ],
+        );
+        let document = extract_epub(&book).unwrap();
+        assert_eq!(
+            chapters(&document),
+            [("One.", Some("Chapter I")), ("Two.", Some("Chapter II"))]
+        );
+    }
+}
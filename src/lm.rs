@@ -1,12 +1,22 @@
-use std::collections::HashSet;
-use std::sync::OnceLock;
+use std::cell::RefCell;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
 
 use anyhow::{bail, Context};
 use llama_cpp_2::{
-    context::params::LlamaContextParams,
+    context::{params::LlamaContextParams, LlamaContext},
     llama_backend::LlamaBackend,
     llama_batch::LlamaBatch,
     model::{params::LlamaModelParams, LlamaModel},
+    token::LlamaToken,
+};
+
+use crate::{
+    chunker::{chunk_text, Chunk},
+    config::{self, CollectionSettings},
+    extract::Document,
 };
 
 static LLAMA_CPP_BACKEND: OnceLock<LlamaBackend> = OnceLock::new();
@@ -15,93 +25,220 @@ pub fn get_llama_backend() -> &'static LlamaBackend {
     LLAMA_CPP_BACKEND.get_or_init(|| LlamaBackend::init().unwrap())
 }
 
-pub fn get_embedding_model(backend: &LlamaBackend) -> anyhow::Result<LlamaModel> {
-    let model_params = LlamaModelParams::default();
-    let model = LlamaModel::load_from_file(
-        &backend,
-        "./models/all-minilm-l6-v2-q4_k_m.gguf",
-        &model_params,
-    )
-    .with_context(|| "unable to load model")?;
-    Ok(model)
+/// Models loaded so far, by path. Models are never unloaded, so references to
+/// them can be handed out freely to the UI and the indexing workers.
+static MODELS: Mutex<BTreeMap<PathBuf, &'static LlamaModel>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    /// Contexts created on this thread, by model address and kind. Contexts
+    /// can't be shared between threads, but creating one per call is slow.
+    static CONTEXTS: RefCell<HashMap<(usize, ContextKind), LlamaContext<'static>>> =
+        RefCell::new(HashMap::new());
 }
 
-pub fn get_reranking_model(backend: &LlamaBackend) -> anyhow::Result<LlamaModel> {
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ContextKind {
+    /// Embeds a single query
+    Embedding,
+    /// Embeds many chunks at once, see [`embed_chunks`]
+    BatchEmbedding,
+    /// Scores a query against a passage with a cross-encoder
+    Rerank,
+}
+
+/// Returns the model at `path`, loading it on first use.
+pub fn get_model(path: &Path) -> anyhow::Result<&'static LlamaModel> {
+    // Holding the lock while loading keeps two threads from loading the same
+    // model at once
+    let mut models = MODELS.lock().unwrap();
+    if let Some(model) = models.get(path) {
+        return Ok(model);
+    }
     let model_params = LlamaModelParams::default();
-    let model = LlamaModel::load_from_file(
-        &backend,
-        "./models/jina-reranker-v1-tiny-en.Q8_0.gguf",
-        &model_params,
-    )
-    .with_context(|| "unable to load model")?;
+    let model = LlamaModel::load_from_file(get_llama_backend(), path, &model_params)
+        .with_context(|| format!("unable to load model {}", path.display()))?;
+    let model: &'static LlamaModel = Box::leak(Box::new(model));
+    models.insert(path.to_path_buf(), model);
     Ok(model)
 }
 
-pub fn tokenize_document_chunks(
-    text: &str,
-    backend: &LlamaBackend,
-    model: &LlamaModel,
-) -> anyhow::Result<Vec<(String, Vec<f32>)>> {
-    let stopwords: HashSet<&'static str> = [
-        "the", "a", "an", "and", "or", "but", "to", "of", "in", "on", "for", "is", "it", "that",
-        "this", "with", "as", "at", "by", "from",
-    ]
-    .into_iter()
-    .collect();
-
-    // Split, normalize, filter out stopwords
-    let words: Vec<String> = text
-        .split_whitespace()
-        .map(|w| w.to_lowercase())
-        .filter(|w| !stopwords.contains(w.as_str()))
-        .collect();
-    let text = words.join(" ");
-    let tokens = model.str_to_token(&text, llama_cpp_2::model::AddBos::Never)?;
-    let chunks: Vec<_> = tokens.chunks(model.n_ctx_train() as usize).collect();
+/// Runs `f` with this thread's context of the given kind for `model`,
+/// creating it on first use.
+fn with_context<T>(
+    model: &'static LlamaModel,
+    kind: ContextKind,
+    f: impl FnOnce(&mut LlamaContext<'static>) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    CONTEXTS.with(|contexts| {
+        let mut contexts = contexts.borrow_mut();
+        let key = (model as *const LlamaModel as usize, kind);
+        let ctx = match contexts.entry(key) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(new_context(model, kind)?),
+        };
+        f(ctx)
+    })
+}
 
+fn new_context(
+    model: &'static LlamaModel,
+    kind: ContextKind,
+) -> anyhow::Result<LlamaContext<'static>> {
     let ctx_params = LlamaContextParams::default()
-        .with_n_threads_batch(std::thread::available_parallelism()?.get().try_into()?)
+        .with_n_threads(n_threads()?)
+        .with_n_threads_batch(n_threads()?)
         .with_embeddings(true);
-    let mut ctx = model
-        .new_context(&backend, ctx_params)
-        .with_context(|| "unable to create the llama_context")?;
-    let mut batch = LlamaBatch::new(model.n_ctx_train() as usize, 1);
+    let ctx_params = match kind {
+        ContextKind::Embedding => ctx_params,
+        ContextKind::BatchEmbedding => {
+            let (n_batch, n_seqs) = batch_shape(model);
+            ctx_params
+                .with_n_ctx(NonZeroU32::new(n_batch as u32))
+                .with_n_batch(n_batch as u32)
+                // Non-causal models need each sequence to be in one ubatch
+                .with_n_ubatch(n_batch as u32)
+                .with_n_seq_max(n_seqs as u32)
+        }
+        ContextKind::Rerank => ctx_params
+            .with_pooling_type(llama_cpp_2::context::params::LlamaPoolingType::Rank)
+            .with_n_ubatch(model.n_ctx_train() / 2)
+            .with_n_batch(model.n_ctx_train()),
+    };
+    model
+        .new_context(get_llama_backend(), ctx_params)
+        .with_context(|| "unable to create the llama_context")
+}
 
+fn n_threads() -> anyhow::Result<i32> {
+    Ok(config::get().indexing.threads().try_into()?)
+}
+
+/// Upper bound on the tokens decoded together by [`embed_chunks`].
+const BATCH_TOKENS: usize = 4096;
+/// Upper bound on the sequences decoded together by [`embed_chunks`].
+const BATCH_SEQUENCES: usize = 64;
+
+/// Chunks of a document along with their tokens.
+pub type TokenizedChunks = Vec<(Chunk, Vec<LlamaToken>)>;
+
+/// Splits `document` into overlapping chunks of about the collection's chunk
+/// size in tokens, following its structure, and returns each chunk along with
+/// its tokens. Chunks keep the extracted text, which is also what gets
+/// embedded.
+pub fn tokenize_document_chunks(
+    document: &Document,
+    model: &LlamaModel,
+    collection: &CollectionSettings,
+) -> anyhow::Result<TokenizedChunks> {
+    let chunk_size = chunk_size(model, collection.chunk_size);
+    let overlap = collection.chunk_overlap.min(chunk_size / 2);
+    let count_tokens = |s: &str| {
+        model
+            .str_to_token(s, llama_cpp_2::model::AddBos::Never)
+            .map(|t| t.len())
+            .unwrap_or(1)
+    };
+
+    let mut chunks = chunk_text(
+        &document.text,
+        &document.regions,
+        chunk_size,
+        overlap,
+        count_tokens,
+    );
+    document.map_offsets(&mut chunks);
     let mut results = vec![];
-    for chunk in chunks {
-        let s = model.tokens_to_str(chunk, llama_cpp_2::model::Special::Tokenize)?;
+    for mut chunk in chunks {
+        let tokens = tokenize_chunk(&chunk.text, model, collection)?;
+        chunk.token_count = tokens.len();
+        results.push((chunk, tokens));
+    }
+    Ok(results)
+}
 
-        batch.add_sequence(chunk, 0, false)?;
-        ctx.clear_kv_cache();
-        ctx.decode(&mut batch)
-            .with_context(|| "llama_decode() failed")?;
+/// Tokenizes the text of a chunk for embedding, cut to the chunk size.
+pub fn tokenize_chunk(
+    text: &str,
+    model: &LlamaModel,
+    collection: &CollectionSettings,
+) -> anyhow::Result<Vec<LlamaToken>> {
+    let mut tokens = model.str_to_token(text, llama_cpp_2::model::AddBos::Never)?;
+    // Sentences tokenize slightly differently in context, and a single word
+    // may be longer than a chunk
+    tokens.truncate(chunk_size(model, collection.chunk_size));
+    Ok(tokens)
+}
+
+fn chunk_size(model: &LlamaModel, chunk_size: usize) -> usize {
+    chunk_size.min(model.n_ctx_train() as usize)
+}
+
+/// Returns the number of tokens and sequences decoded together by
+/// [`embed_chunks`].
+fn batch_shape(model: &LlamaModel) -> (usize, usize) {
+    // Every sequence gets room for a full chunk of any collection, so this
+    // also works for models that split the context between sequences.
+    let largest = config::get()
+        .collections()
+        .iter()
+        .map(|c| c.chunk_size)
+        .max()
+        .unwrap_or(1);
+    let max_chunk = chunk_size(model, largest);
+    let n_seqs = (BATCH_TOKENS / max_chunk).clamp(1, BATCH_SEQUENCES);
+    (n_seqs * max_chunk, n_seqs)
+}
+
+/// Embeds tokenized chunks, packing as many of them as fit into each batch as
+/// separate sequences. Embeddings are returned in the order of `chunks`.
+pub fn embed_chunks(
+    chunks: &[&[LlamaToken]],
+    model: &'static LlamaModel,
+) -> anyhow::Result<Vec<Vec<f32>>> {
+    if chunks.is_empty() {
+        return Ok(vec![]);
+    }
+    let (n_batch, n_seqs) = batch_shape(model);
+    with_context(model, ContextKind::BatchEmbedding, |ctx| {
+        let mut batch = LlamaBatch::new(n_batch, n_seqs as i32);
+        let mut results = Vec::with_capacity(chunks.len());
+        let mut seq_id = 0;
+        for chunk in chunks {
+            if seq_id == n_seqs || batch.n_tokens() as usize + chunk.len() > n_batch {
+                decode_embeddings(ctx, &mut batch, seq_id, &mut results)?;
+                seq_id = 0;
+            }
+            batch.add_sequence(chunk, seq_id as i32, false)?;
+            seq_id += 1;
+        }
+        decode_embeddings(ctx, &mut batch, seq_id, &mut results)?;
+        Ok(results)
+    })
+}
+
+fn decode_embeddings(
+    ctx: &mut LlamaContext,
+    batch: &mut LlamaBatch,
+    n_seqs: usize,
+    results: &mut Vec<Vec<f32>>,
+) -> anyhow::Result<()> {
+    ctx.clear_kv_cache();
+    ctx.decode(batch).with_context(|| "llama_decode() failed")?;
+    for seq_id in 0..n_seqs {
         let embedding = ctx
-            .embeddings_seq_ith(0)
+            .embeddings_seq_ith(seq_id as i32)
             .with_context(|| "Failed to get embeddings")?;
-        batch.clear();
-        let embedding = normalize(embedding);
-
-        results.push((s, embedding));
+        results.push(normalize(embedding));
     }
-    Ok(results)
+    batch.clear();
+    Ok(())
 }
 
 pub fn get_cross_encoding_rank(
     query: &str,
     s: &str,
-    backend: &LlamaBackend,
-    model: &LlamaModel,
+    model: &'static LlamaModel,
 ) -> anyhow::Result<Vec<f32>> {
-    let ctx_params = LlamaContextParams::default()
-        .with_n_threads_batch(std::thread::available_parallelism()?.get().try_into()?)
-        .with_embeddings(true)
-        .with_pooling_type(llama_cpp_2::context::params::LlamaPoolingType::Rank)
-        .with_n_ubatch(model.n_ctx_train() / 2)
-        .with_n_batch(model.n_ctx_train());
-    let mut ctx = model
-        .new_context(&backend, ctx_params)
-        .with_context(|| "unable to create the llama_context")?;
-
     let text = format!("{query}</s><s>{s}");
     let tokens = model.str_to_token(&text, llama_cpp_2::model::AddBos::Always)?;
 
@@ -109,52 +246,43 @@ pub fn get_cross_encoding_rank(
         bail!("input longer than context length");
     }
 
-    let mut batch = LlamaBatch::new(model.n_ctx_train() as usize, 1);
-    batch.add_sequence(&tokens, 0, false)?;
+    with_context(model, ContextKind::Rerank, |ctx| {
+        let mut batch = LlamaBatch::new(model.n_ctx_train() as usize, 1);
+        batch.add_sequence(&tokens, 0, false)?;
 
-    ctx.clear_kv_cache();
-    ctx.decode(&mut batch)
-        .with_context(|| "llama_decode() failed")?;
-    let embedding = ctx
-        .embeddings_seq_ith(0)
-        .with_context(|| "Failed to get embeddings")?;
-    batch.clear();
-    // let embedding = normalize(embedding);
+        ctx.clear_kv_cache();
+        ctx.decode(&mut batch)
+            .with_context(|| "llama_decode() failed")?;
+        let embedding = ctx
+            .embeddings_seq_ith(0)
+            .with_context(|| "Failed to get embeddings")?;
+        // let embedding = normalize(embedding);
 
-    Ok(embedding.into())
+        Ok(embedding.into())
+    })
 }
 
-pub fn get_embedding(
-    s: &str,
-    backend: &LlamaBackend,
-    model: &LlamaModel,
-) -> anyhow::Result<Vec<f32>> {
-    let ctx_params = LlamaContextParams::default()
-        .with_n_threads_batch(std::thread::available_parallelism()?.get().try_into()?)
-        .with_embeddings(true);
-    let mut ctx = model
-        .new_context(&backend, ctx_params)
-        .with_context(|| "unable to create the llama_context")?;
-
+pub fn get_embedding(s: &str, model: &'static LlamaModel) -> anyhow::Result<Vec<f32>> {
     let tokens = model.str_to_token(s, llama_cpp_2::model::AddBos::Never)?;
 
     if tokens.len() > model.n_ctx_train() as usize {
         bail!("input longer than context length");
     }
 
-    let mut batch = LlamaBatch::new(model.n_ctx_train() as usize, 1);
-    batch.add_sequence(&tokens, 0, false)?;
+    with_context(model, ContextKind::Embedding, |ctx| {
+        let mut batch = LlamaBatch::new(model.n_ctx_train() as usize, 1);
+        batch.add_sequence(&tokens, 0, false)?;
 
-    ctx.clear_kv_cache();
-    ctx.decode(&mut batch)
-        .with_context(|| "llama_decode() failed")?;
-    let embedding = ctx
-        .embeddings_seq_ith(0)
-        .with_context(|| "Failed to get embeddings")?;
-    batch.clear();
-    let embedding = normalize(embedding);
+        ctx.clear_kv_cache();
+        ctx.decode(&mut batch)
+            .with_context(|| "llama_decode() failed")?;
+        let embedding = ctx
+            .embeddings_seq_ith(0)
+            .with_context(|| "Failed to get embeddings")?;
+        let embedding = normalize(embedding);
 
-    Ok(embedding)
+        Ok(embedding)
+    })
 }
 
 fn normalize(input: &[f32]) -> Vec<f32> {
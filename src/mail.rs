@@ -0,0 +1,198 @@
+use std::io::Cursor;
+
+use anyhow::Context;
+use mail_parser::{mailbox::mbox::MessageIterator, Address, Message, MessageParser, MimeHeaders};
+use serde_json::json;
+
+use crate::{
+    chunker::{Format, Metadata},
+    extract::{detect, extract, Document, SAMPLE_LEN},
+    html,
+};
+
+/// Splits an mbox mailbox into its messages, in order.
+pub fn mbox_messages(bytes: &[u8]) -> impl Iterator<Item = anyhow::Result<Vec<u8>>> + '_ {
+    MessageIterator::new(Cursor::new(bytes)).map(|message| {
+        let message = message.context("unable to read mailbox")?;
+        Ok(message.unwrap_contents())
+    })
+}
+
+/// The `Message-ID` of a message, without its angle brackets.
+pub fn message_id(bytes: &[u8]) -> Option<String> {
+    let message = MessageParser::default().parse_headers(bytes)?;
+    let id = message.message_id()?.trim();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+/// Extracts the text of an email message: its headers, its body and the text
+/// of its attachments and forwarded messages. Every region carries the
+/// sender, recipients, subject and date of the message as metadata.
+pub fn extract_message(bytes: &[u8]) -> anyhow::Result<Document> {
+    let message = MessageParser::default()
+        .parse(bytes)
+        .context("invalid email message")?;
+    let mut document = Document::default();
+    message_text(&message, &mut document);
+    Ok(document)
+}
+
+fn message_text(message: &Message, document: &mut Document) {
+    let mut metadata = Metadata::new();
+    let mut headers = vec![];
+    let fields = [
+        ("from", message.from().map(address_text)),
+        ("to", message.to().map(address_text)),
+        ("subject", message.subject().map(str::to_string)),
+        ("date", message.date().map(|d| d.to_rfc3339())),
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            headers.push(format!(
+                "{}{}: {value}",
+                name[..1].to_uppercase(),
+                &name[1..]
+            ));
+            metadata.insert(name.into(), json!(value));
+        }
+    }
+    // The headers get a region of their own, so that searches for a sender or
+    // a subject find the message
+    if !headers.is_empty() {
+        document.push(&headers.join("\n"), Format::Text, metadata.clone());
+    }
+
+    for part in message.text_bodies() {
+        let Some(text) = part.text_contents() else {
+            continue;
+        };
+        if part.is_text_html() {
+            document.append(html::extract_html(text), &metadata);
+        } else {
+            document.push(text.trim_end(), Format::Text, metadata.clone());
+        }
+    }
+
+    for part in message.attachments() {
+        if let Some(forwarded) = part.message() {
+            message_text(forwarded, document);
+            continue;
+        }
+        let name = part.attachment_name().unwrap_or("attachment");
+        let content = part.contents();
+        let file_type = detect(name, &content[..content.len().min(SAMPLE_LEN as usize)]);
+        if file_type.kind.is_archive() {
+            continue;
+        }
+        // Images and the like have no text
+        if let Ok(attachment) = extract(name, file_type.kind, content.to_vec()) {
+            let mut metadata = metadata.clone();
+            metadata.insert("attachment".into(), json!(name));
+            document.append(attachment, &metadata);
+        }
+    }
+}
+
+/// Formats addresses as `Name <address>`, separated by commas.
+fn address_text(address: &Address) -> String {
+    address
+        .iter()
+        .filter_map(|a| match (a.name(), a.address()) {
+            (Some(name), Some(address)) => Some(format!("{name} <{address}>")),
+            (name, address) => name.or(address).map(str::to_string),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Whether `sample` starts like an email message: a block of header fields
+/// including at least `From` and `Date`, which every message has.
+pub fn looks_like_message(sample: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(sample);
+    let mut names = vec![];
+    for line in text.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with([' ', '\t']) && !names.is_empty() {
+            continue;
+        }
+        let Some((name, _)) = line.split_once(':') else {
+            return false;
+        };
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic()) {
+            return false;
+        }
+        names.push(name.to_ascii_lowercase());
+    }
+    names.iter().any(|n| n == "from") && names.iter().any(|n| n == "date")
+}
+
+/// Whether `sample` starts like an mbox mailbox: a `From ` separator line
+/// followed by a message.
+pub fn looks_like_mbox(sample: &[u8]) -> bool {
+    sample.starts_with(b"From ")
+        && sample
+            .iter()
+            .position(|&b| b == b'\n')
+            .is_some_and(|i| looks_like_message(&sample[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"From: Ann <ann@example.com>
+Date: Mon, 1 Jan 2024 10:00:00 +0000
+Subject: Lunch
+Message-ID: <1@example.com>
+
+Noon?
+";
+
+    #[test]
+    fn recognizes_messages() {
+        assert!(looks_like_message(MESSAGE));
+        assert!(looks_like_message(
+            b"Date: x\nX-Folded: a\n b\nfrom: y\n\nbody"
+        ));
+        // Both From and Date are needed
+        assert!(!looks_like_message(b"From: a\nSubject: b\n\nbody"));
+        assert!(!looks_like_message(b"From: a\nnot a header\nDate: b\n"));
+        assert!(!looks_like_message(b"# Notes\nFrom: a\nDate: b\n"));
+        assert!(!looks_like_message(b""));
+    }
+
+    #[test]
+    fn recognizes_mailboxes() {
+        let mbox = [b"From ann@example.com Mon Jan  1 10:00:00 2024\n", MESSAGE].concat();
+        assert!(looks_like_mbox(&mbox));
+        assert!(!looks_like_mbox(MESSAGE));
+        assert!(!looks_like_mbox(b"From here on, text.\nMore text.\n"));
+    }
+
+    #[test]
+    fn splits_mailboxes_into_messages() {
+        let mbox = [
+            b"From ann@example.com Mon Jan  1 10:00:00 2024\n",
+            MESSAGE,
+            b"\nFrom bob@example.com Mon Jan  1 11:00:00 2024\n",
+            b"From: bob@example.com\nDate: Mon, 1 Jan 2024 11:00:00 +0000\n\nYes\n",
+        ]
+        .concat();
+        let messages: Vec<Vec<u8>> = mbox_messages(&mbox).collect::<Result<_, _>>().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(message_id(&messages[0]).as_deref(), Some("1@example.com"));
+        assert_eq!(message_id(&messages[1]), None);
+    }
+
+    #[test]
+    fn extracts_headers_and_body() {
+        let document = extract_message(MESSAGE).unwrap();
+        assert_eq!(
+            document.text,
+            "From: Ann <ann@example.com>\nSubject: Lunch\nDate: 2024-01-01T10:00:00Z\n\nNoon?"
+        );
+        assert_eq!(document.regions[1].metadata["subject"], "Lunch");
+    }
+}
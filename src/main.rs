@@ -1,16 +1,29 @@
 #![allow(non_snake_case)]
+use std::path::PathBuf;
 use std::rc::Rc;
 
+use clap::Parser;
 use dioxus::prelude::*;
-use r2d2::{Pool, PooledConnection};
+use r2d2::PooledConnection;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{ffi::sqlite3_auto_extension, params};
-use sqlite_vec::sqlite3_vec_init;
 
-use crate::workers::dir_scanner;
+use crate::{cli::Command, config::Config, workers::spawn_scanner};
 
+mod archive;
+mod chunker;
+mod cli;
+mod config;
+mod db;
+mod extract;
+mod html;
 mod lm;
+mod mail;
+mod notebook;
+mod office;
+mod rules;
 mod search;
+mod sources;
+mod watcher;
 mod workers;
 
 const FAVICON: Asset = asset!("/assets/favicon.ico");
@@ -18,72 +31,56 @@ const MAIN_CSS: Asset = asset!("/assets/main.css");
 
 pub type AppDb = Rc<PooledConnection<SqliteConnectionManager>>;
 
-fn main() -> anyhow::Result<()> {
-    unsafe {
-        sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
-    }
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Path to the config file [default: $XDG_CONFIG_HOME/lmtools/config.toml]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 
-    let manager = SqliteConnectionManager::file("data.sqlite");
-    let pool = Pool::builder().max_size(10).build(manager)?;
+    /// Run headless instead of launching the UI
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    config::init(Config::load(cli.config.as_deref())?);
 
+    let pool = db::open_pool(&config::get().database)?;
     {
         let conn = pool.get()?;
-        conn.execute_batch(
-            r#"
-PRAGMA journal_mode=WAL;
-
--- Directories to scan
-CREATE TABLE IF NOT EXISTS dir_queue (
-    id INTEGER PRIMARY KEY AUTOINCREMENT,
-    path TEXT UNIQUE NOT NULL,
-    status TEXT CHECK(status IN ('pending', 'scanning', 'done', 'error')) DEFAULT 'pending',
-    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
-);
-
--- Files to index
-CREATE TABLE IF NOT EXISTS file_queue (
-    id INTEGER PRIMARY KEY AUTOINCREMENT,
-    path TEXT UNIQUE NOT NULL,
-    dir_id INTEGER REFERENCES dir_queue(id) ON DELETE CASCADE,
-    status TEXT CHECK(status IN ('pending', 'scanning', 'done', 'error')) DEFAULT 'pending',
-    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-    error TEXT
-);
-
--- Actual full-text search table
-CREATE VIRTUAL TABLE IF NOT EXISTS documents USING fts5(
-    file_path UNINDEXED,
-    chunk_index UNINDEXED,
-    content
-);
-
-CREATE VIRTUAL TABLE IF NOT EXISTS embeddings USING vec0(
-    file_path TEXT,
-    chunk_index INTEGER,
-    content TEXT,
-    embedding float[384]
-);
-            "#,
-        )?;
-        // let cwd = std::env::current_dir()?.canonicalize()?;
-        // let path = cwd.as_os_str().to_str().unwrap_or_else(|| "");
-        let path = "/home/nk/Documents/Gutenberg_Text/Austen, Jane";
-        conn.execute(
-            "INSERT OR IGNORE INTO dir_queue (path) VALUES (?);",
-            params![path],
-        )?;
+        for collection in config::get().collections() {
+            for root in collection.roots {
+                db::add_root(&conn, &root.path, Some(collection.name))?;
+            }
+        }
     }
 
-    let _pool = pool.clone();
-    let _h2 = std::thread::Builder::new().spawn(move || {
-        if let Err(e) = dir_scanner(_pool) {
-            eprintln!("Error in dir scanner: {e:?}");
+    if let Some(command) = cli.command {
+        return cli::run(command, pool);
+    }
+
+    db::rescan(&*pool.get()?)?;
+
+    spawn_scanner(pool.clone())?;
+    watcher::spawn_watcher(pool.clone())?;
+    // Load the models up front, so that the first search does not have to
+    std::thread::spawn(|| {
+        for collection in config::get().collections() {
+            for model in [collection.embedding, collection.reranking] {
+                if let Err(e) = lm::get_model(model) {
+                    eprintln!("{e:?}");
+                }
+            }
         }
-    })?;
+    });
 
+    let _pool = pool.clone();
     #[allow(deprecated)]
     LaunchBuilder::new()
-        .with_context_provider(move || Box::new(Rc::new(pool.get().unwrap())))
+        .with_context_provider(move || Box::new(Rc::new(_pool.get().unwrap())))
+        .with_context_provider(move || Box::new(pool.clone()))
         .launch(App);
 
     Ok(())
@@ -103,12 +100,19 @@ fn Home() -> Element {
     search::Search()
 }
 
+#[component]
+fn Sources() -> Element {
+    sources::Sources()
+}
+
 #[derive(Debug, Clone, Routable, PartialEq)]
 #[rustfmt::skip]
 enum Route {
     #[layout(Navbar)]
     #[route("/")]
     Home {},
+    #[route("/sources")]
+    Sources {},
     #[route("/:..segments")]
     PageNotFound { segments: Vec<String> },
 }
@@ -123,6 +127,10 @@ fn Navbar() -> Element {
                 to: Route::Home {},
                 "Home"
             }
+            Link {
+                to: Route::Sources {},
+                "Sources"
+            }
         }
         div {
             class: "main",
@@ -0,0 +1,121 @@
+use anyhow::Context;
+use serde_json::{json, Value};
+
+use crate::{
+    chunker::{Format, Metadata},
+    extract::Document,
+};
+
+/// Extracts the cells of a Jupyter notebook: markdown cells as Markdown, code
+/// cells as code followed by their text outputs. Images and other binary
+/// outputs, stored base64-encoded, are left out. Every region carries the
+/// number of its cell as metadata.
+pub fn extract_notebook(text: &str) -> anyhow::Result<Document> {
+    let notebook: Value = serde_json::from_str(text).context("invalid notebook")?;
+    let cells = notebook
+        .get("cells")
+        .and_then(Value::as_array)
+        .context("invalid notebook: no cells")?;
+    let mut document = Document::default();
+    for (i, cell) in cells.iter().enumerate() {
+        let mut metadata = Metadata::new();
+        metadata.insert("cell".into(), json!(i + 1));
+        let source = multiline(cell.get("source"));
+        let format = match cell.get("cell_type").and_then(Value::as_str) {
+            Some("markdown") => Format::Markdown,
+            Some("code") => Format::Code,
+            _ => Format::Text,
+        };
+        if !source.trim().is_empty() {
+            document.push(source.trim_end(), format, metadata.clone());
+        }
+        let outputs = cell.get("outputs").and_then(Value::as_array);
+        for output in outputs.into_iter().flatten() {
+            let text = output_text(output);
+            if !text.trim().is_empty() {
+                document.push(text.trim_end(), Format::Text, metadata.clone());
+            }
+        }
+    }
+    Ok(document)
+}
+
+/// The text of a code cell's output, if any.
+fn output_text(output: &Value) -> String {
+    match output.get("output_type").and_then(Value::as_str) {
+        Some("stream") => multiline(output.get("text")),
+        Some("execute_result" | "display_data") => {
+            multiline(output.get("data").and_then(|d| d.get("text/plain")))
+        }
+        Some("error") => {
+            let field = |name| output.get(name).and_then(Value::as_str).unwrap_or("");
+            format!("{}: {}", field("ename"), field("evalue"))
+        }
+        _ => String::new(),
+    }
+}
+
+/// Notebooks store multiline text either as a string or as a list of lines.
+fn multiline(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(lines)) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_cells_and_text_outputs() {
+        let notebook = r##"{
+            "cells": [
+                {"cell_type": "markdown", "source": ["# Title\n", "Intro"]},
+                {"cell_type": "code", "source": "print(1)\n1 + 1", "outputs": [
+                    {"output_type": "stream", "text": ["1\n"]},
+                    {"output_type": "execute_result", "data": {"text/plain": "2", "image/png": "iVBOR"}},
+                    {"output_type": "display_data", "data": {"image/png": "iVBOR"}}
+                ]},
+                {"cell_type": "code", "source": "", "outputs": []},
+                {"cell_type": "code", "source": "1 / 0", "outputs": [
+                    {"output_type": "error", "ename": "ZeroDivisionError", "evalue": "division by zero"}
+                ]}
+            ]
+        }"##;
+        let document = extract_notebook(notebook).unwrap();
+        let regions: Vec<(&str, Format, &Value)> = document
+            .regions
+            .iter()
+            .map(|r| {
+                (
+                    &document.text[r.start..r.end],
+                    r.format,
+                    &r.metadata["cell"],
+                )
+            })
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                ("# Title\nIntro", Format::Markdown, &json!(1)),
+                ("print(1)\n1 + 1", Format::Code, &json!(2)),
+                ("1", Format::Text, &json!(2)),
+                ("2", Format::Text, &json!(2)),
+                ("1 / 0", Format::Code, &json!(4)),
+                (
+                    "ZeroDivisionError: division by zero",
+                    Format::Text,
+                    &json!(4)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_other_json() {
+        assert!(extract_notebook("{}").is_err());
+        assert!(extract_notebook("not json").is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use std::io::Cursor;
+
+use anyhow::Context;
+use calamine::Reader;
+use roxmltree::Node;
+use serde_json::json;
+use zip::ZipArchive;
+
+use crate::{
+    chunker::{Format, Metadata},
+    extract::{read_zip_entry, Document},
+};
+
+/// Extracts the body of a Word document. Headings are kept as Markdown
+/// headings, list items as Markdown list items and table rows as lines.
+pub fn extract_docx(bytes: &[u8]) -> anyhow::Result<Document> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).context("invalid DOCX archive")?;
+    let xml = read_zip_entry(&mut archive, "word/document.xml")?;
+    let xml = roxmltree::Document::parse(&xml).context("invalid DOCX document")?;
+    let body = xml
+        .descendants()
+        .find(|n| n.tag_name().name() == "body")
+        .context("DOCX document has no body")?;
+    let mut blocks = vec![];
+    docx_blocks(body, &mut blocks);
+    Ok(markdown_document(blocks))
+}
+
+/// Extracts the body of an OpenDocument text document, in the same shape as
+/// [`extract_docx`].
+pub fn extract_odt(bytes: &[u8]) -> anyhow::Result<Document> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).context("invalid ODT archive")?;
+    let xml = read_zip_entry(&mut archive, "content.xml")?;
+    let xml = roxmltree::Document::parse(&xml).context("invalid ODT content")?;
+    let body = xml
+        .descendants()
+        .find(|n| {
+            n.tag_name().name() == "text"
+                && n.parent().is_some_and(|p| p.tag_name().name() == "body")
+        })
+        .context("ODT document has no text body")?;
+    let mut blocks = vec![];
+    odt_blocks(body, &mut blocks);
+    Ok(markdown_document(blocks))
+}
+
+/// Extracts every sheet of a spreadsheet, one region per sheet with a line
+/// per row, so that chunks cite their sheet and rows.
+pub fn extract_spreadsheet(bytes: &[u8]) -> anyhow::Result<Document> {
+    let mut workbook =
+        calamine::open_workbook_auto_from_rs(Cursor::new(bytes)).context("invalid spreadsheet")?;
+    let mut document = Document::default();
+    for name in workbook.sheet_names() {
+        let range = workbook
+            .worksheet_range(&name)
+            .with_context(|| format!("unable to read sheet {name}"))?;
+        let Some((first_row, _)) = range.start() else {
+            continue;
+        };
+        let rows: Vec<String> = range
+            .rows()
+            .map(|row| row_text(row.iter().map(|c| c.to_string())))
+            .collect();
+        let text = rows.join("\n");
+        if text.trim().is_empty() {
+            continue;
+        }
+        let mut metadata = Metadata::new();
+        metadata.insert("sheet".into(), json!(name));
+        let format = Format::Table {
+            first_row: first_row as usize + 1,
+            header: false,
+        };
+        document.push(&text, format, metadata);
+    }
+    Ok(document)
+}
+
+/// Extracts the rows of a CSV file, or of a TSV file if `delimiter` is a tab,
+/// in the same shape as a sheet of [`extract_spreadsheet`]. The first row is
+/// taken for a header and repeated in every chunk.
+pub fn extract_csv(text: &str, delimiter: u8) -> anyhow::Result<Document> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record.context("invalid CSV file")?;
+        rows.push(row_text(record.iter().map(str::to_string)));
+    }
+    let mut document = Document::default();
+    let format = Format::Table {
+        first_row: 1,
+        header: true,
+    };
+    document.push(&rows.join("\n"), format, Metadata::new());
+    Ok(document)
+}
+
+/// Renders the cells of a table row on one line, separated by `|`, without
+/// the empty cells at the end.
+fn row_text(cells: impl Iterator<Item = String>) -> String {
+    let cells: Vec<String> = cells
+        .map(|c| c.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect();
+    let used = cells
+        .iter()
+        .rposition(|c| !c.is_empty())
+        .map_or(0, |i| i + 1);
+    cells[..used].join(" | ")
+}
+
+/// Joins paragraphs, separated by blank lines, into a single Markdown region.
+fn markdown_document(blocks: Vec<String>) -> Document {
+    let text = blocks
+        .iter()
+        .map(|b| b.trim())
+        .filter(|b| !b.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let mut document = Document::default();
+    document.push(&text, Format::Markdown, Metadata::new());
+    document
+}
+
+fn docx_blocks(node: Node, blocks: &mut Vec<String>) {
+    for child in node.children().filter(Node::is_element) {
+        match child.tag_name().name() {
+            "p" => {
+                let mut text = String::new();
+                inline_text(child, &mut text);
+                let properties = child.children().find(|n| n.tag_name().name() == "pPr");
+                let heading = properties.and_then(|p| {
+                    p.children().find_map(|n| match n.tag_name().name() {
+                        "pStyle" => {
+                            let style = attribute(n, "val")?.to_ascii_lowercase();
+                            if style == "title" {
+                                return Some(1);
+                            }
+                            style.strip_prefix("heading")?.trim().parse::<usize>().ok()
+                        }
+                        // Level 9 is body text
+                        "outlineLvl" => attribute(n, "val")?
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|l| *l < 9)
+                            .map(|l| l + 1),
+                        _ => None,
+                    })
+                });
+                let list_item = properties
+                    .is_some_and(|p| p.children().any(|n| n.tag_name().name() == "numPr"));
+                blocks.push(match heading {
+                    Some(level) if !text.trim().is_empty() => {
+                        format!("{} {}", "#".repeat(level.clamp(1, 6)), text.trim())
+                    }
+                    _ if list_item => format!("- {}", text.trim()),
+                    _ => text,
+                });
+            }
+            "tbl" => blocks.push(table_rows(child, "tr", "tc")),
+            // Content controls and the like wrap ordinary paragraphs
+            _ => docx_blocks(child, blocks),
+        }
+    }
+}
+
+fn odt_blocks(node: Node, blocks: &mut Vec<String>) {
+    for child in node.children().filter(Node::is_element) {
+        match child.tag_name().name() {
+            "h" => {
+                let level = attribute(child, "outline-level")
+                    .and_then(|l| l.parse::<usize>().ok())
+                    .unwrap_or(1);
+                let mut text = String::new();
+                inline_text(child, &mut text);
+                blocks.push(format!("{} {}", "#".repeat(level.clamp(1, 6)), text.trim()));
+            }
+            "p" => {
+                let mut text = String::new();
+                inline_text(child, &mut text);
+                blocks.push(text);
+            }
+            "list-item" => {
+                let mut items = vec![];
+                odt_blocks(child, &mut items);
+                for item in items {
+                    blocks.push(format!("- {}", item.trim()));
+                }
+            }
+            "table" => blocks.push(table_rows(child, "table-row", "table-cell")),
+            "tracked-changes" | "annotation" | "sequence-decls" => {}
+            // Lists, sections and the like wrap ordinary paragraphs
+            _ => odt_blocks(child, blocks),
+        }
+    }
+}
+
+/// Renders the rows of a table as lines of cells separated by `|`.
+fn table_rows(table: Node, row_tag: &str, cell_tag: &str) -> String {
+    let mut lines = vec![];
+    for row in table
+        .descendants()
+        .filter(|n| n.tag_name().name() == row_tag)
+    {
+        let cells: Vec<String> = row
+            .children()
+            .filter(|n| n.tag_name().name() == cell_tag)
+            .map(|cell| {
+                let mut text = String::new();
+                inline_text(cell, &mut text);
+                text.split_whitespace().collect::<Vec<_>>().join(" ")
+            })
+            .collect();
+        if cells.iter().any(|c| !c.is_empty()) {
+            lines.push(cells.join(" | "));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Appends the text under `node`, in either Word or OpenDocument markup.
+fn inline_text(node: Node, out: &mut String) {
+    for child in node.children() {
+        if child.is_text() {
+            let text = child.text().unwrap_or("");
+            // Skip indentation between elements of pretty-printed files
+            if !(text.contains('\n') && text.trim().is_empty()) {
+                out.push_str(text);
+            }
+            continue;
+        }
+        match child.tag_name().name() {
+            "tab" => out.push('\t'),
+            "br" | "cr" | "line-break" => out.push('\n'),
+            // OpenDocument collapses runs of spaces into `<text:s text:c="n"/>`
+            "s" => {
+                let n = attribute(child, "c")
+                    .and_then(|c| c.parse().ok())
+                    .unwrap_or(1);
+                out.push_str(&" ".repeat(n));
+            }
+            // Paragraphs nested in cells or notes
+            "p" if !out.is_empty() => {
+                out.push('\n');
+                inline_text(child, out);
+            }
+            "delText" | "instrText" | "pPr" | "rPr" | "note-citation" | "annotation" => {}
+            _ => inline_text(child, out),
+        }
+    }
+}
+
+/// Returns the attribute of `node` with the given local name, whatever its
+/// namespace.
+fn attribute<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attributes()
+        .find(|a| a.name() == name)
+        .map(|a| a.value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn docx_text(body: &str) -> String {
+        let xml = format!(
+            r#"<w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main"><w:body>{body}</w:body></w:document>"#
+        );
+        let xml = roxmltree::Document::parse(&xml).unwrap();
+        let body = xml
+            .descendants()
+            .find(|n| n.tag_name().name() == "body")
+            .unwrap();
+        let mut blocks = vec![];
+        docx_blocks(body, &mut blocks);
+        markdown_document(blocks).text
+    }
+
+    fn odt_text(body: &str) -> String {
+        let xml = format!(
+            r#"<office:text xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0" xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0" xmlns:table="urn:oasis:names:tc:opendocument:xmlns:table:1.0">{body}</office:text>"#
+        );
+        let xml = roxmltree::Document::parse(&xml).unwrap();
+        let mut blocks = vec![];
+        odt_blocks(xml.root_element(), &mut blocks);
+        markdown_document(blocks).text
+    }
+
+    #[test]
+    fn renders_docx_headings_lists_and_tables() {
+        let text = docx_text(
+            r#"<w:p><w:pPr><w:pStyle w:val="Title"/></w:pPr><w:r><w:t>Report</w:t></w:r></w:p>
+            <w:p><w:pPr><w:pStyle w:val="Heading2"/></w:pPr><w:r><w:t>Results</w:t></w:r></w:p>
+            <w:p><w:pPr><w:outlineLvl w:val="2"/></w:pPr><w:r><w:t>Details</w:t></w:r></w:p>
+            <w:p><w:pPr><w:numPr/></w:pPr><w:r><w:t>First</w:t></w:r></w:p>
+            <w:p><w:r><w:t xml:space="preserve">Plain </w:t></w:r><w:r><w:delText>gone</w:delText><w:t>text</w:t></w:r></w:p>
+            <w:tbl><w:tr><w:tc><w:p><w:r><w:t>a</w:t></w:r></w:p></w:tc><w:tc><w:p><w:r><w:t>b</w:t></w:r></w:p></w:tc></w:tr></w:tbl>"#,
+        );
+        assert_eq!(
+            text,
+            "# Report\n\n## Results\n\n### Details\n\n- First\n\nPlain text\n\na | b"
+        );
+    }
+
+    #[test]
+    fn treats_docx_outline_level_9_as_body_text() {
+        let text = docx_text(
+            r#"<w:p><w:pPr><w:outlineLvl w:val="9"/></w:pPr><w:r><w:t>Body</w:t></w:r></w:p>
+            <w:p><w:pPr><w:outlineLvl w:val="8"/></w:pPr><w:r><w:t>Deep</w:t></w:r></w:p>"#,
+        );
+        assert_eq!(text, "Body\n\n###### Deep");
+    }
+
+    #[test]
+    fn renders_odt_headings_lists_and_tables() {
+        let text = odt_text(
+            r#"<text:h text:outline-level="2">Results</text:h>
+            <text:p>Two<text:s text:c="2"/>spaces</text:p>
+            <text:list><text:list-item><text:p>First</text:p></text:list-item></text:list>
+            <table:table><table:table-row><table:table-cell><text:p>a</text:p></table:table-cell><table:table-cell><text:p>b</text:p></table:table-cell></table:table-row></table:table>"#,
+        );
+        assert_eq!(text, "## Results\n\nTwo  spaces\n\n- First\n\na | b");
+    }
+
+    #[test]
+    fn extracts_csv_rows_with_a_header() {
+        let document =
+            extract_csv("name,note\nann,\"a, b\"\nbob,\"two\nlines\",extra\n", b',').unwrap();
+        assert_eq!(
+            document.text,
+            "name | note\nann | a, b\nbob | two lines | extra"
+        );
+        assert_eq!(
+            document.regions[0].format,
+            Format::Table {
+                first_row: 1,
+                header: true
+            }
+        );
+        let document = extract_csv("a\tb\n1\t2\n", b'\t').unwrap();
+        assert_eq!(document.text, "a | b\n1 | 2");
+    }
+
+    #[test]
+    fn drops_empty_trailing_cells() {
+        let cells = ["a", " b  c ", "", "d", "", ""].map(String::from);
+        assert_eq!(row_text(cells.into_iter()), "a | b c |  | d");
+    }
+}
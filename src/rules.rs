@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use ignore::{
+    gitignore::GitignoreBuilder,
+    overrides::{Override, OverrideBuilder},
+    Walk, WalkBuilder,
+};
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{
+    config::{self, RootConfig},
+    extract::{detect, Kind, SAMPLE_LEN},
+    workers::is_skipped_dir,
+};
+
+/// Names of the ignore files honored in every directory, from lowest to
+/// highest precedence.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore"];
+
+/// Which files below a root are indexed: the root's include and exclude
+/// globs and size limit, the hidden files option, and the `.gitignore` and
+/// `.ignore` files found along the way.
+pub struct Rules {
+    root: PathBuf,
+    /// Matches files left out by the root's `include` globs.
+    includes: Override,
+    /// Matches files and directories left out by the root's `exclude` globs.
+    excludes: Override,
+    hidden: bool,
+    max_file_size: u64,
+}
+
+impl Rules {
+    pub fn new(root: &RootConfig) -> anyhow::Result<Self> {
+        let indexing = &config::get().indexing;
+        let globs = |globs: &[String], prefix: &str| -> anyhow::Result<Override> {
+            let mut builder = OverrideBuilder::new(&root.path);
+            for glob in globs {
+                builder.add(&format!("{prefix}{glob}")).with_context(|| {
+                    format!("invalid glob {glob:?} in root {}", root.path.display())
+                })?;
+            }
+            Ok(builder.build()?)
+        };
+        Ok(Self {
+            root: root.path.clone(),
+            includes: globs(&root.include, "")?,
+            excludes: globs(&root.exclude, "!")?,
+            hidden: root.hidden.unwrap_or(indexing.hidden()),
+            max_file_size: root.max_file_size.unwrap_or(indexing.max_file_size()),
+        })
+    }
+
+    /// The rules for `path`, from the configuration of the innermost root
+    /// containing it, in any collection. Roots added at runtime rather than in
+    /// the configuration file follow the defaults.
+    pub fn for_path(conn: &Connection, path: &Path) -> anyhow::Result<Self> {
+        let collections = config::get().collections();
+        let configured = collections
+            .iter()
+            .flat_map(|c| c.roots)
+            .filter(|r| path.starts_with(&r.path))
+            .max_by_key(|r| r.path.as_os_str().len());
+        if let Some(root) = configured {
+            return Self::new(root);
+        }
+        let root: Option<String> = conn
+            .query_row(
+                r#"
+                SELECT path FROM roots
+                WHERE ?1 = path OR substr(?1, 1, length(path) + 1) = path || '/'
+                ORDER BY length(path) DESC LIMIT 1
+                "#,
+                [path.to_str()],
+                |r| r.get(0),
+            )
+            .optional()?;
+        Self::new(&RootConfig::new(
+            root.map_or_else(|| path.to_path_buf(), PathBuf::from),
+        ))
+    }
+
+    /// Lists the entries of the directory `dir` that the rules let through.
+    /// The directory itself comes first, at depth 0.
+    pub fn walk(&self, dir: &Path) -> Walk {
+        let includes = self.includes.clone();
+        WalkBuilder::new(dir)
+            .max_depth(Some(1))
+            .follow_links(false)
+            .hidden(!self.hidden)
+            .parents(true)
+            .ignore(true)
+            .git_ignore(true)
+            .git_global(false)
+            .git_exclude(false)
+            .require_git(false)
+            .overrides(self.excludes.clone())
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                if is_dir {
+                    entry.depth() == 0 || !entry.file_name().to_str().is_some_and(is_skipped_dir)
+                } else {
+                    !includes.matched(entry.path(), false).is_ignore()
+                }
+            })
+            .build()
+    }
+
+    /// Whether the file at `path`, of `size` bytes, is too large to index.
+    /// Mailboxes are exempt, they are unpacked message by message within
+    /// `indexing.max_archive_size` like archives.
+    pub fn too_large(&self, path: &Path, size: u64) -> bool {
+        size > self.max_file_size && !is_mailbox(path)
+    }
+
+    /// Whether `path` is left out by the rules, for paths reported by the
+    /// watcher rather than found by [`Rules::walk`].
+    pub fn excludes(&self, path: &Path, is_dir: bool) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        let depth = relative.components().count();
+        let mut below = PathBuf::new();
+        for (i, component) in relative.components().enumerate() {
+            below.push(component);
+            let name = component.as_os_str().to_str().unwrap_or("");
+            let component_is_dir = is_dir || i + 1 < depth;
+            if (component_is_dir && is_skipped_dir(name))
+                || (!self.hidden && name.starts_with('.'))
+                || self
+                    .excludes
+                    .matched(self.root.join(&below), component_is_dir)
+                    .is_ignore()
+            {
+                return true;
+            }
+        }
+        if !is_dir && self.includes.matched(path, false).is_ignore() {
+            return true;
+        }
+        // The innermost ignore file that mentions the path or one of its
+        // parents decides
+        for dir in path.ancestors().skip(1) {
+            let mut builder = GitignoreBuilder::new(dir);
+            for name in IGNORE_FILES {
+                let file = dir.join(name);
+                if file.is_file() {
+                    builder.add(file);
+                }
+            }
+            let Ok(ignore) = builder.build() else {
+                continue;
+            };
+            let matched = ignore.matched_path_or_any_parents(path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+fn is_mailbox(path: &Path) -> bool {
+    let (Some(name), Ok(file)) = (path.to_str(), File::open(path)) else {
+        return false;
+    };
+    let mut sample = vec![];
+    if file.take(SAMPLE_LEN).read_to_end(&mut sample).is_err() {
+        return false;
+    }
+    detect(name, &sample).kind == Kind::Mbox
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// A directory holding the configured roots, and files to walk.
+    fn root() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("lmtools-rules-{}", std::process::id()));
+        let config = format!(
+            r#"
+            roots = [{{ path = "{root}", exclude = ["drafts", "*.log"] }}]
+            [collections.work]
+            roots = [{{ path = "{root}/work", include = ["*.md"], hidden = true, max_file_size = 10 }}]
+            "#,
+            root = root.display()
+        );
+        config::set_for_tests(toml::from_str(&config).unwrap());
+        for dir in ["drafts", "sub", "target", "work"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for (file, content) in [
+            (".gitignore", "*.tmp\n!keep.tmp\n"),
+            ("sub/.ignore", "keep.tmp\n"),
+            ("a.txt", ""),
+            ("a.tmp", ""),
+            ("keep.tmp", ""),
+            ("x.log", ""),
+            (".hidden", ""),
+            (
+                "work/Inbox",
+                "From a@b Mon Jan  1 00:00:00 2024\nFrom: a@b\nDate: Mon, 1 Jan 2024\n\nHi\n",
+            ),
+        ] {
+            fs::write(root.join(file), content).unwrap();
+        }
+        root
+    }
+
+    fn rules(path: &Path) -> Rules {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE roots (path TEXT)", []).unwrap();
+        Rules::for_path(&conn, path).unwrap()
+    }
+
+    #[test]
+    fn uses_the_innermost_configured_root() {
+        let root = root();
+        let outer = rules(&root.join("a.txt"));
+        assert_eq!(outer.root, root);
+        assert!(!outer.too_large(&root.join("a.txt"), 11));
+        let inner = rules(&root.join("work/a.md"));
+        assert_eq!(inner.root, root.join("work"));
+        assert!(inner.too_large(&root.join("work/a.md"), 11));
+        // Mailboxes are only limited by their size as archives
+        assert!(!inner.too_large(&root.join("work/Inbox"), 11));
+        assert!(!inner.excludes(&root.join("work/a.md"), false));
+        assert!(inner.excludes(&root.join("work/a.txt"), false));
+        assert!(!inner.excludes(&root.join("work/.a.md"), false));
+        assert!(inner.excludes(&root.join("work/target"), true));
+    }
+
+    #[test]
+    fn falls_back_to_roots_added_at_runtime() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE roots (path TEXT)", []).unwrap();
+        conn.execute("INSERT INTO roots VALUES ('/added'), ('/added/inner')", [])
+            .unwrap();
+        // Installs a configuration with no roots above these
+        root();
+        let rules = Rules::for_path(&conn, Path::new("/added/inner/a.txt")).unwrap();
+        assert_eq!(rules.root, Path::new("/added/inner"));
+        let rules = Rules::for_path(&conn, Path::new("/added-not/a.txt")).unwrap();
+        assert_eq!(rules.root, Path::new("/added-not/a.txt"));
+    }
+
+    #[test]
+    fn excludes_globs_hidden_files_and_ignored_files() {
+        let root = root();
+        let rules = rules(&root);
+        let excluded = |path: &str, is_dir| rules.excludes(&root.join(path), is_dir);
+        assert!(!excluded("a.txt", false));
+        assert!(excluded("x.log", false));
+        assert!(excluded("drafts", true));
+        assert!(excluded("drafts/a.txt", false));
+        assert!(excluded(".hidden", false));
+        assert!(excluded("target/a.txt", false));
+        assert!(excluded("a.tmp", false));
+        assert!(!excluded("keep.tmp", false));
+        // The innermost ignore file decides
+        assert!(excluded("sub/keep.tmp", false));
+        assert!(!excluded("sub/a.txt", false));
+        assert!(!rules.excludes(Path::new("/elsewhere/x.log"), false));
+    }
+
+    #[test]
+    fn walks_only_entries_the_rules_let_through() {
+        let root = root();
+        let mut names: Vec<String> = rules(&root)
+            .walk(&root)
+            .filter_map(Result::ok)
+            .filter(|e| e.depth() == 1)
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a.txt", "keep.tmp", "sub", "work"]);
+    }
+}
@@ -1,12 +1,17 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
 use dioxus::prelude::*;
 use rusqlite::params;
+use serde::Serialize;
 use zerocopy::IntoBytes;
 
 use crate::{
-    lm::{
-        get_cross_encoding_rank, get_embedding, get_embedding_model, get_llama_backend,
-        get_reranking_model,
-    },
+    archive,
+    chunker::{describe, Metadata},
+    config,
+    db::{active_index, building_index, find_collections, list_collections, Collection},
+    lm::{get_cross_encoding_rank, get_embedding, get_model},
     AppDb,
 };
 
@@ -14,15 +19,24 @@ use crate::{
 pub fn Search() -> Element {
     let mut query = use_signal(|| "".to_string());
     let mut search_results: Signal<Vec<FTSResult>> = use_signal(|| vec![]);
+    // Collections to search, all of them if none is selected
+    let mut selected: Signal<Vec<String>> = use_signal(|| vec![]);
     let mut status = use_resource(|| async move {
         let conn: crate::AppDb = consume_context();
         let scan_status = get_scan_status(conn).unwrap();
 
         scan_status
     });
+    let collections = use_resource(|| async move {
+        let conn: crate::AppDb = consume_context();
+        list_collections(&conn).unwrap_or_else(|e| {
+            eprintln!("{e:?}");
+            vec![]
+        })
+    });
     let search = move |q: String| async move {
         let conn: crate::AppDb = consume_context();
-        let sr = match fts(conn, &q) {
+        let sr = match fts(conn, &q, &selected.cloned()) {
             Err(e) => {
                 eprintln!("{e:?}");
                 vec![]
@@ -93,6 +107,35 @@ pub fn Search() -> Element {
                     ">"
                 }
             }
+            if collections.cloned().unwrap_or_default().len() > 1 {
+                div {
+                    style: "
+                    flex-grow: 0;
+                    display: flex;
+                    flex-direction: row;
+                    ",
+                    for collection in collections.cloned().unwrap_or_default() {
+                        label {
+                            key: "{collection.id}",
+                            input {
+                                r#type: "checkbox",
+                                checked: selected.read().contains(&collection.name),
+                                onchange: {
+                                    let name = collection.name.clone();
+                                    move |e: FormEvent| {
+                                        if e.checked() {
+                                            selected.write().push(name.clone());
+                                        } else {
+                                            selected.write().retain(|n| *n != name);
+                                        }
+                                    }
+                                },
+                            }
+                            "{collection.name}"
+                        }
+                    }
+                }
+            }
             div {
                 style: "
                 flex-grow: 1;
@@ -100,10 +143,18 @@ pub fn Search() -> Element {
                 ",
                 for r in search_results.cloned() {
                     div {
-                        "{r.file_path} {r.chunk_index} {r.score}"
+                        style: if r.stale { "color: gray;" } else { "" },
+                        "[{r.collection}] {r.file_path}:{r.line_start}-{r.line_end} {r.score}"
+                        if let Some(context) = describe(&r.metadata) {
+                            " ({context})"
+                        }
+                        if r.stale {
+                            " (file no longer exists)"
+                        }
                         div {
                             style: "
                             font-size: 10px;
+                            white-space: pre-wrap;
                             ",
                             "{r.chunk}"
                         }
@@ -114,63 +165,151 @@ pub fn Search() -> Element {
     }
 }
 
-#[derive(Clone)]
-struct FTSResult {
-    file_path: String,
-    chunk_index: usize,
-    chunk: String,
-    score: f32,
+#[derive(Clone, Serialize)]
+pub(crate) struct FTSResult {
+    /// Collection the chunk was indexed into.
+    pub(crate) collection: String,
+    pub(crate) file_path: String,
+    pub(crate) chunk_index: usize,
+    pub(crate) chunk: String,
+    /// Location of the chunk, offsets are in bytes and end exclusive, lines
+    /// are 1-based. Offsets are into the file for text files, whatever their
+    /// encoding, and into the extracted text for other formats.
+    pub(crate) byte_start: usize,
+    pub(crate) byte_end: usize,
+    pub(crate) line_start: usize,
+    pub(crate) line_end: usize,
+    /// Where the chunk sits in the document's structure, e.g. its headings.
+    pub(crate) metadata: Metadata,
+    pub(crate) score: f32,
+    /// The file no longer exists, the index has not caught up yet.
+    pub(crate) stale: bool,
 }
 
-fn fts(conn: AppDb, query: &str) -> anyhow::Result<Vec<FTSResult>> {
+fn parse_metadata(json: Option<String>) -> anyhow::Result<Metadata> {
+    Ok(match json {
+        Some(json) => serde_json::from_str(&json)?,
+        None => Metadata::new(),
+    })
+}
+
+/// Searches the collections called `collections`, or every collection if it
+/// is empty.
+pub(crate) fn fts(
+    conn: AppDb,
+    query: &str,
+    collections: &[String],
+) -> anyhow::Result<Vec<FTSResult>> {
+    let collections = find_collections(&conn, collections)?;
+    let ids: Vec<i64> = collections.iter().map(|c| c.id).collect();
     let mut results = vec![];
     let mut stmt = conn.prepare(
         r#"
-        SELECT file_path, chunk_index, content, bm25(documents) AS score
+        SELECT file_path, chunk_index, content, byte_start, byte_end, line_start, line_end,
+            metadata, bm25(documents) AS score, collection_id
         FROM documents
-        WHERE documents MATCH ?1
+        WHERE documents MATCH ?1 AND collection_id IN (SELECT value FROM json_each(?2))
         ORDER BY score
         LIMIT 10;
         "#,
     )?;
-    let mut rows = stmt.query(params![query])?;
+    let mut rows = stmt.query(params![query, serde_json::to_string(&ids)?])?;
     while let Some(row) = rows.next()? {
-        results.push(FTSResult {
-            file_path: row.get(0)?,
-            chunk_index: row.get(1)?,
-            chunk: row.get(2)?,
-            score: row.get(3)?,
-        });
+        let id: i64 = row.get(9)?;
+        let collection = collections.iter().find(|c| c.id == id);
+        results.push(read_result(
+            row,
+            collection.map_or("", |c| c.name.as_str()),
+        )?);
     }
-    let backend = get_llama_backend();
-    let embedding = {
-        let embedding_model = get_embedding_model(backend)?;
-        get_embedding(query, backend, &embedding_model)?
+    // Every collection has embeddings of its own, from its own model
+    for collection in &collections {
+        vector_search(&conn, query, collection, &mut results)?;
+    }
+    rerank(query, &collections, results)
+}
+
+/// Reads a result from the first columns of `row`, as selected by [`fts`].
+fn read_result(row: &rusqlite::Row, collection: &str) -> anyhow::Result<FTSResult> {
+    Ok(FTSResult {
+        collection: collection.to_string(),
+        file_path: row.get(0)?,
+        chunk_index: row.get(1)?,
+        chunk: row.get(2)?,
+        byte_start: row.get(3)?,
+        byte_end: row.get(4)?,
+        line_start: row.get(5)?,
+        line_end: row.get(6)?,
+        metadata: parse_metadata(row.get(7)?)?,
+        score: row.get(8)?,
+        stale: false,
+    })
+}
+
+/// Adds the chunks of `collection` whose embeddings are closest to that of
+/// `query` to `results`.
+fn vector_search(
+    conn: &AppDb,
+    query: &str,
+    collection: &Collection,
+    results: &mut Vec<FTSResult>,
+) -> anyhow::Result<()> {
+    // While embeddings are rebuilt for a new model, the query is embedded with
+    // the model of the index that is still active
+    let Some(index) = active_index(conn, collection.id)? else {
+        return Ok(());
     };
-    let mut stmt = conn.prepare(
+    let model = match &index.model {
+        Some(path) => get_model(Path::new(path))?,
+        None => get_model(config::get().collection(&collection.name).embedding)?,
+    };
+    let embedding = get_embedding(query, model)?;
+    if embedding.len() != index.dimension {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare(&format!(
         r#"
-        SELECT file_path, chunk_index, content, distance
-        FROM embeddings
+        SELECT file_path, chunk_index, content, byte_start, byte_end, line_start, line_end,
+            metadata, distance
+        FROM {}
         WHERE embedding MATCH ?
         ORDER BY distance
         LIMIT 10;
         "#,
-    )?;
+        index.name
+    ))?;
     let mut rows = stmt.query(params![embedding.as_bytes()])?;
     while let Some(row) = rows.next()? {
-        results.push(FTSResult {
-            file_path: row.get(0)?,
-            chunk_index: row.get(1)?,
-            chunk: row.get(2)?,
-            score: row.get(3)?,
-        });
+        results.push(read_result(row, &collection.name)?);
     }
-    let model = get_reranking_model(backend)?;
+    Ok(())
+}
+
+/// Scores `results` against `query`, best first. Scores of different
+/// cross-encoders do not compare, so results from `collections` with
+/// different reranking models are all scored with the top-level one.
+fn rerank(
+    query: &str,
+    collections: &[Collection],
+    mut results: Vec<FTSResult>,
+) -> anyhow::Result<Vec<FTSResult>> {
+    let config = config::get();
+    let mut models = collections
+        .iter()
+        .map(|c| config.collection(&c.name).reranking);
+    let path = match models.next() {
+        Some(path) if models.all(|p| p == path) => path,
+        _ => config.models.reranking.as_path(),
+    };
+    let model = get_model(path)?;
     for r in &mut results {
-        let rank = get_cross_encoding_rank(query, &r.chunk, backend, &model)?;
+        let rank = get_cross_encoding_rank(query, &r.chunk, model)?;
         // println!("{:?}", rank);
         r.score = rank[0];
     }
+    for r in &mut results {
+        r.stale = !Path::new(archive::real_path(&r.file_path)).exists();
+    }
     results.sort_by(|a, b| {
         a.score
             .partial_cmp(&b.score)
@@ -181,12 +320,27 @@ fn fts(conn: AppDb, query: &str) -> anyhow::Result<Vec<FTSResult>> {
     Ok(results)
 }
 
-#[derive(Default, Clone)]
-struct FilesScanStatus {
-    pending: u64,
-    scanning: u64,
-    done: u64,
-    error: u64,
+#[derive(Default, Clone, Serialize)]
+pub(crate) struct FilesScanStatus {
+    pub(crate) pending: u64,
+    pub(crate) scanning: u64,
+    pub(crate) done: u64,
+    pub(crate) error: u64,
+    /// Files by detected MIME type.
+    pub(crate) types: BTreeMap<String, u64>,
+    pub(crate) collections: Vec<CollectionStatus>,
+}
+
+#[derive(Default, Clone, Serialize)]
+pub(crate) struct CollectionStatus {
+    pub(crate) name: String,
+    /// Indexed chunks.
+    pub(crate) chunks: u64,
+    /// Model of the embeddings searches use.
+    pub(crate) embedding_model: Option<String>,
+    /// Model embeddings are being rebuilt with, after it replaced
+    /// `embedding_model` in the configuration.
+    pub(crate) rebuilding: Option<String>,
 }
 
 impl FilesScanStatus {
@@ -198,11 +352,13 @@ impl FilesScanStatus {
             scanning: (self.scanning as f64 / total * 100.0) as u64,
             done: (self.done as f64 / total * 100.0) as u64,
             error: (self.error as f64 / total * 100.0) as u64,
+            types: BTreeMap::new(),
+            collections: vec![],
         }
     }
 }
 
-fn get_scan_status(conn: AppDb) -> anyhow::Result<FilesScanStatus> {
+pub(crate) fn get_scan_status(conn: AppDb) -> anyhow::Result<FilesScanStatus> {
     let mut stmt = conn.prepare(
         r#"
 WITH all_statuses(status) AS (
@@ -220,12 +376,7 @@ GROUP BY
         "#,
     )?;
     let mut rows = stmt.query([])?;
-    let mut scan_status = FilesScanStatus {
-        pending: 0,
-        scanning: 0,
-        done: 0,
-        error: 0,
-    };
+    let mut scan_status = FilesScanStatus::default();
     while let Some(row) = rows.next()? {
         let status: String = row.get(0)?;
         let count: i64 = row.get(1)?;
@@ -248,5 +399,27 @@ GROUP BY
         }
     }
 
+    let mut stmt =
+        conn.prepare("SELECT mime, COUNT(*) FROM file_queue WHERE mime IS NOT NULL GROUP BY mime")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let count: i64 = row.get(1)?;
+        scan_status.types.insert(row.get(0)?, count as u64);
+    }
+
+    for collection in list_collections(&conn)? {
+        let chunks: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM documents WHERE collection_id = ?",
+            [collection.id],
+            |r| r.get(0),
+        )?;
+        scan_status.collections.push(CollectionStatus {
+            chunks: chunks as u64,
+            embedding_model: active_index(&conn, collection.id)?.and_then(|i| i.model),
+            rebuilding: building_index(&conn, collection.id)?.and_then(|i| i.model),
+            name: collection.name,
+        });
+    }
+
     Ok(scan_status)
 }
@@ -0,0 +1,138 @@
+use std::path::{Path, PathBuf};
+
+use dioxus::prelude::*;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use crate::{
+    db::{add_root, list_roots, remove_root, rescan, Root},
+    watcher::{unwatch, watch},
+    workers::spawn_scanner,
+    AppDb,
+};
+
+#[component]
+pub fn Sources() -> Element {
+    let mut new_root = use_signal(|| "".to_string());
+    let mut collection = use_signal(|| "".to_string());
+    let mut error: Signal<Option<String>> = use_signal(|| None);
+    let mut roots = use_resource(|| async move {
+        let conn: AppDb = consume_context();
+        list_roots(&conn).unwrap_or_else(|e| {
+            eprintln!("{e:?}");
+            vec![]
+        })
+    });
+    let add = move |path: String, collection: String| -> anyhow::Result<()> {
+        let path = PathBuf::from(path.trim()).canonicalize()?;
+        if !path.is_dir() {
+            anyhow::bail!("{} is not a directory", path.display());
+        }
+        let collection = Some(collection.trim()).filter(|name| !name.is_empty());
+        let conn: AppDb = consume_context();
+        add_root(&conn, &path, collection)?;
+        watch(&path)?;
+        let pool: Pool<SqliteConnectionManager> = consume_context();
+        spawn_scanner(pool)?;
+        Ok(())
+    };
+    let rescan_all = move || -> anyhow::Result<()> {
+        let conn: AppDb = consume_context();
+        rescan(&conn)?;
+        let pool: Pool<SqliteConnectionManager> = consume_context();
+        spawn_scanner(pool)
+    };
+    let remove = move |path: String| -> anyhow::Result<()> {
+        let conn: AppDb = consume_context();
+        unwatch(Path::new(&path))?;
+        remove_root(&conn, &path)
+    };
+    let root_list: Vec<Root> = roots.cloned().unwrap_or_default();
+    rsx! {
+        div {
+            style: "
+            display: flex;
+            flex-direction: column;
+            margin: 1px;
+            height: calc(100% - 4px);
+            ",
+            div {
+                style: "
+                flex-grow: 0;
+                display: flex;
+                flex-direction: row;
+                ",
+                input {
+                    style: "flex-grow: 1;",
+                    placeholder: "/path/to/directory",
+                    value: new_root.cloned(),
+                    oninput: move |e| { new_root.set(e.value()); },
+                },
+                input {
+                    style: "flex-grow: 0;",
+                    placeholder: "collection",
+                    value: collection.cloned(),
+                    oninput: move |e| { collection.set(e.value()); },
+                },
+                button {
+                    style: "flex-grow: 0;",
+                    onclick: move |_| {
+                        let path = new_root.cloned();
+                        if path.trim().is_empty() { return; }
+                        match add(path, collection.cloned()) {
+                            Ok(()) => {
+                                new_root.set("".to_string());
+                                error.set(None);
+                            }
+                            Err(e) => error.set(Some(format!("{e}"))),
+                        }
+                        roots.restart();
+                    },
+                    "Add"
+                }
+                button {
+                    style: "flex-grow: 0;",
+                    onclick: move |_| {
+                        if let Err(e) = rescan_all() {
+                            error.set(Some(format!("{e}")));
+                        }
+                        roots.restart();
+                    },
+                    "Rescan"
+                }
+            }
+            if let Some(e) = error.cloned() {
+                div { style: "color: red;", "{e}" }
+            }
+            div {
+                style: "
+                flex-grow: 1;
+                overflow: auto;
+                ",
+                for root in root_list {
+                    div {
+                        key: "{root.id}",
+                        style: "
+                        display: flex;
+                        flex-direction: row;
+                        ",
+                        div {
+                            style: "flex-grow: 1;",
+                            "{root.path} [{root.collection}] ({root.done}/{root.files} files indexed)"
+                        }
+                        button {
+                            style: "flex-grow: 0;",
+                            onclick: move |_| {
+                                if let Err(e) = remove(root.path.clone()) {
+                                    error.set(Some(format!("{e}")));
+                                }
+                                roots.restart();
+                            },
+                            "Remove"
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
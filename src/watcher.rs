@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{
+    db::{collection_of, list_roots, move_path, purge_path},
+    rules::Rules,
+    workers::{is_skipped_dir, queue_dir, queue_file, spawn_scanner},
+};
+
+/// How long one half of a rename waits for its other half before it is
+/// handled as a plain create or delete.
+const RENAME_TIMEOUT: Duration = Duration::from_millis(500);
+
+static WATCHER: Mutex<Option<RecommendedWatcher>> = Mutex::new(None);
+
+/// Starts watching every indexed root, keeping the queues in sync with the
+/// filesystem while the app is running.
+pub fn spawn_watcher(pool: Pool<SqliteConnectionManager>) -> anyhow::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let watcher = notify::recommended_watcher(tx)?;
+    *WATCHER.lock().unwrap() = Some(watcher);
+    for root in list_roots(&*pool.get()?)? {
+        if let Err(e) = watch(Path::new(&root.path)) {
+            eprintln!("Unable to watch {}: {e:?}", root.path);
+        }
+    }
+    std::thread::Builder::new()
+        .name("watcher".into())
+        .spawn(move || {
+            if let Err(e) = handle_events(pool, rx) {
+                eprintln!("Error in watcher: {e:?}");
+            }
+        })?;
+    Ok(())
+}
+
+/// Starts watching `path`. Does nothing if the watcher is not running.
+pub fn watch(path: &Path) -> anyhow::Result<()> {
+    if let Some(watcher) = WATCHER.lock().unwrap().as_mut() {
+        watcher.watch(path, RecursiveMode::Recursive)?;
+    }
+    Ok(())
+}
+
+/// Stops watching `path`. Does nothing if the watcher is not running.
+pub fn unwatch(path: &Path) -> anyhow::Result<()> {
+    if let Some(watcher) = WATCHER.lock().unwrap().as_mut() {
+        watcher.unwatch(path)?;
+    }
+    Ok(())
+}
+
+/// Half of a rename, waiting to be matched with the other half.
+struct PendingRename {
+    path: PathBuf,
+    from: bool,
+    deadline: Instant,
+}
+
+fn handle_events(
+    pool: Pool<SqliteConnectionManager>,
+    rx: mpsc::Receiver<notify::Result<Event>>,
+) -> anyhow::Result<()> {
+    let mut renames: HashMap<usize, Vec<PendingRename>> = HashMap::new();
+    loop {
+        let timeout = renames
+            .values()
+            .flatten()
+            .map(|r| r.deadline.saturating_duration_since(Instant::now()))
+            .min()
+            .unwrap_or(Duration::from_secs(3600));
+        let event = match rx.recv_timeout(timeout) {
+            Ok(Ok(event)) => Some(event),
+            Ok(Err(e)) => {
+                eprintln!("Watch error: {e:?}");
+                None
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+
+        let conn = pool.get()?;
+        let mut changed = false;
+
+        // Unmatched halves of renames are moves into or out of the watched tree
+        let now = Instant::now();
+        renames.retain(|_, halves| {
+            halves.retain(|r| {
+                if r.deadline > now {
+                    return true;
+                }
+                let result = if r.from {
+                    removed(&conn, &r.path)
+                } else {
+                    created(&conn, &r.path)
+                };
+                if let Err(e) = result {
+                    eprintln!("Unable to update {}: {e:?}", r.path.display());
+                }
+                changed = true;
+                false
+            });
+            !halves.is_empty()
+        });
+
+        if let Some(event) = event.filter(|e| !e.paths.iter().any(|p| is_skipped(p))) {
+            let result = match event.kind {
+                EventKind::Create(_) | EventKind::Modify(ModifyKind::Data(_)) => {
+                    event.paths.iter().try_for_each(|p| created(&conn, p))
+                }
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                    if let Some(tracker) = event.tracker() {
+                        renames.remove(&tracker);
+                    }
+                    match &event.paths[..] {
+                        [from, to] => moved(&conn, from, to),
+                        _ => Ok(()),
+                    }
+                }
+                EventKind::Modify(ModifyKind::Name(mode @ (RenameMode::From | RenameMode::To))) => {
+                    let from = mode == RenameMode::From;
+                    match event.tracker() {
+                        Some(tracker) => {
+                            let deadline = Instant::now() + RENAME_TIMEOUT;
+                            renames
+                                .entry(tracker)
+                                .or_default()
+                                .extend(event.paths.iter().map(|p| PendingRename {
+                                    path: p.clone(),
+                                    from,
+                                    deadline,
+                                }));
+                            Ok(())
+                        }
+                        None if from => event.paths.iter().try_for_each(|p| removed(&conn, p)),
+                        None => event.paths.iter().try_for_each(|p| created(&conn, p)),
+                    }
+                }
+                EventKind::Modify(ModifyKind::Name(_)) => {
+                    // The backend could not tell which side of the rename this
+                    // is, so look at what is on disk.
+                    event.paths.iter().try_for_each(|p| {
+                        if p.exists() {
+                            created(&conn, p)
+                        } else {
+                            removed(&conn, p)
+                        }
+                    })
+                }
+                EventKind::Remove(_) => event.paths.iter().try_for_each(|p| removed(&conn, p)),
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                eprintln!("Unable to handle {event:?}: {e:?}");
+            }
+            changed = true;
+        }
+
+        if changed {
+            spawn_scanner(pool.clone())?;
+        }
+    }
+}
+
+fn is_skipped(path: &Path) -> bool {
+    path.components()
+        .any(|c| c.as_os_str().to_str().is_some_and(is_skipped_dir))
+}
+
+fn created(conn: &Connection, path: &Path) -> anyhow::Result<()> {
+    let Some(path_str) = path.to_str() else {
+        return Ok(());
+    };
+    let Ok(md) = std::fs::symlink_metadata(path) else {
+        // Already gone again
+        return Ok(());
+    };
+    if md.is_symlink() {
+        return Ok(());
+    }
+    let rules = Rules::for_path(conn, path)?;
+    if rules.excludes(path, md.is_dir()) || (!md.is_dir() && rules.too_large(path, md.len())) {
+        // It may have been indexed before it grew, or before it was moved here
+        return removed(conn, path);
+    }
+    if md.is_dir() {
+        return queue_dir(conn, path_str);
+    }
+    let dir_id: Option<i64> = match path.parent().and_then(Path::to_str) {
+        Some(parent) => conn
+            .query_row("SELECT id FROM dir_queue WHERE path = ?", [parent], |r| {
+                r.get(0)
+            })
+            .optional()?,
+        None => None,
+    };
+    queue_file(conn, dir_id, path_str, &md)
+}
+
+fn removed(conn: &Connection, path: &Path) -> anyhow::Result<()> {
+    let Some(path) = path.to_str() else {
+        return Ok(());
+    };
+    let tx = conn.unchecked_transaction()?;
+    purge_path(&tx, path)?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn moved(conn: &Connection, from: &Path, to: &Path) -> anyhow::Result<()> {
+    let (Some(from_str), Some(to_str)) = (from.to_str(), to.to_str()) else {
+        return Ok(());
+    };
+    if collection_of(conn, from_str)? != collection_of(conn, to_str)? {
+        // Its chunks were embedded with the model of the other collection
+        removed(conn, from)?;
+        return created(conn, to);
+    }
+    move_path(conn, from_str, to_str)?;
+    // A file moved over an existing one may have been modified as well
+    created(conn, to)
+}
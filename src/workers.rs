@@ -1,221 +1,977 @@
-use llama_cpp_2::{llama_backend::LlamaBackend, model::LlamaModel};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use llama_cpp_2::{model::LlamaModel, token::LlamaToken};
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, OptionalExtension};
+use rusqlite::{params, Connection, OptionalExtension};
 use zerocopy::IntoBytes;
 
-use crate::lm::{get_embedding_model, get_llama_backend, tokenize_document_chunks};
+use crate::{
+    archive,
+    chunker::Chunk,
+    config,
+    db::{self, delete_chunks, move_chunks, purge_path, Collection, EmbeddingIndex},
+    extract,
+    lm::{embed_chunks, get_model, tokenize_chunk, tokenize_document_chunks, TokenizedChunks},
+    rules::Rules,
+};
+
+static SCANNER_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Base delay before a failed file or directory is retried. Doubles with every
+/// failed attempt.
+const RETRY_DELAY_SECS: i64 = 30;
+
+/// Runs [`dir_scanner`] on a background thread, unless one is already running,
+/// in which case it will pick up any newly queued work by itself.
+pub fn spawn_scanner(pool: Pool<SqliteConnectionManager>) -> anyhow::Result<()> {
+    if SCANNER_RUNNING.swap(true, Ordering::AcqRel) {
+        return Ok(());
+    }
+    std::thread::Builder::new()
+        .name("dir_scanner".into())
+        .spawn(move || loop {
+            let result = dir_scanner(pool.clone());
+            SCANNER_RUNNING.store(false, Ordering::Release);
+            if let Err(e) = result {
+                eprintln!("Error in dir scanner: {e:?}");
+                return;
+            }
+            // Work queued after the scanner saw empty queues, but before the
+            // flag was cleared, would otherwise be left pending. The same goes
+            // for failed work waiting to be retried, and for work claimed by
+            // a process that died, once its lease runs out.
+            match next_pending(&pool) {
+                Ok(Some(delay)) => {
+                    std::thread::sleep(delay);
+                    if SCANNER_RUNNING.swap(true, Ordering::AcqRel) {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Error in dir scanner: {e:?}");
+                    return;
+                }
+            }
+        })?;
+    Ok(())
+}
 
+/// Returns how long until the next pending row can be claimed, or until the
+/// lease of a row left `scanning` by another process runs out, or `None` if
+/// nothing is pending or leased.
+fn next_pending(pool: &Pool<SqliteConnectionManager>) -> anyhow::Result<Option<Duration>> {
+    let conn = pool.get()?;
+    let wait: Option<i64> = conn.query_row(
+        r#"
+        SELECT MAX(MIN(ready_at) - unixepoch(), 0) FROM (
+            SELECT COALESCE(next_attempt_at, 0) AS ready_at FROM dir_queue WHERE status = 'pending'
+            UNION ALL
+            SELECT COALESCE(next_attempt_at, 0) FROM file_queue WHERE status = 'pending'
+            UNION ALL
+            SELECT COALESCE(leased_at, 0) + ?1 FROM dir_queue WHERE status = 'scanning'
+            UNION ALL
+            SELECT COALESCE(leased_at, 0) + ?1 FROM file_queue WHERE status = 'scanning'
+        )
+        "#,
+        [config::get().indexing.lease_timeout() as i64],
+        |r| r.get(0),
+    )?;
+    Ok(wait.map(|secs| Duration::from_secs(secs as u64)))
+}
+
+/// Crawls the queued directories and indexes the queued files with a pool of
+/// workers, until the queues are empty. Files are embedded with the model of
+/// their collection. If the embedding model of a collection changed, the
+/// embeddings of the files indexed with the previous one are rebuilt
+/// alongside.
 pub fn dir_scanner(pool: Pool<SqliteConnectionManager>) -> anyhow::Result<()> {
-    let backend = get_llama_backend();
-    let model = get_embedding_model(backend)?;
+    let conn = pool.get()?;
+    // Leases can run out at any time, not just before the first scan
+    db::reclaim_stale_leases(&conn, config::get().indexing.lease_timeout())?;
+    for collection in db::list_collections(&conn)? {
+        sync_collection(&conn, &collection)?;
+    }
+    drop(conn);
+    // Workers keep polling for new files while the rebuild runs
+    let busy = AtomicUsize::new(1);
+    std::thread::scope(|s| {
+        let mut workers: Vec<_> = (0..config::get().indexing.workers())
+            .map(|_| s.spawn(|| scan_worker(&pool, &busy)))
+            .collect();
+        workers.push(s.spawn(|| {
+            let result = rebuild_embeddings(&pool);
+            busy.fetch_sub(1, Ordering::SeqCst);
+            result
+        }));
+        workers.into_iter().try_for_each(|w| {
+            w.join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("indexing worker panicked")))
+        })
+    })?;
+    // scan finished, moves have been matched up by now
+    reconcile(&*pool.get()?)
+}
+
+/// Makes sure that the chunks of `collection` are embedded into an index for
+/// its configured embedding model, and returns that model.
+fn sync_collection(
+    conn: &Connection,
+    collection: &Collection,
+) -> anyhow::Result<&'static LlamaModel> {
+    let model_path = config::get().collection(&collection.name).embedding;
+    let model = get_model(model_path)?;
+    let Some(model_path) = model_path.to_str() else {
+        anyhow::bail!("model path {} is not valid UTF-8", model_path.display());
+    };
+    db::sync_embedding_index(conn, collection.id, model_path, model.n_embd() as usize)?;
+    Ok(model)
+}
+
+fn scan_worker(pool: &Pool<SqliteConnectionManager>, busy: &AtomicUsize) -> anyhow::Result<()> {
     let conn = pool.get()?;
     loop {
         conn.cache_flush()?;
-        // std::thread::sleep(Duration::from_millis(10));
-        if !scan_1_dir(&conn)? {
-            if !scan_1_file(&conn, backend, &model).unwrap() {
-                // scan finished
+        busy.fetch_add(1, Ordering::SeqCst);
+        let worked = match scan_1_dir(&conn) {
+            Ok(false) => scan_files(&conn),
+            other => other,
+        };
+        let others_busy = busy.fetch_sub(1, Ordering::SeqCst) - 1;
+        if !worked? {
+            if others_busy == 0 {
                 return Ok(());
             }
+            // Another worker may still queue files from the directory it is
+            // crawling
+            std::thread::sleep(Duration::from_millis(100));
         }
     }
 }
 
 fn scan_1_dir(conn: &PooledConnection<SqliteConnectionManager>) -> anyhow::Result<bool> {
-    let mut stmt =
-        conn.prepare("SELECT id, path FROM dir_queue WHERE status = 'pending' LIMIT 1")?;
-
-    let mut rows = stmt.query([])?;
-
-    let Some(row) = rows.next()? else {
+    let Some((lease, path)) = claim(conn, "dir_queue", 1)?.pop() else {
         return Ok(false);
     };
-    let id: i64 = row.get(0)?;
-    let path: String = row.get(1)?;
-
-    conn.execute("UPDATE dir_queue SET status='scanning' WHERE id=?", [id])
-        .unwrap();
-
-    for entry in std::fs::read_dir(path).unwrap() {
-        let entry = entry.unwrap();
-        let p = entry.path();
-        let entry_path = p.as_os_str().to_str().unwrap();
-        let is_symlink = entry
-            .metadata()
-            .map(|md| md.is_symlink())
-            .unwrap_or_else(|_| false);
-        if is_symlink {
+    if let Err(e) = list_dir(conn, lease.id, &path).and_then(|()| succeed(conn, &lease)) {
+        fail(conn, &lease, &format!("{e:#}"))?;
+    }
+    Ok(true)
+}
+
+/// Queues the files and subdirectories of `path` that the indexing rules let
+/// through, and drops those that earlier crawls found but the rules now leave
+/// out.
+fn list_dir(conn: &Connection, id: i64, path: &str) -> anyhow::Result<()> {
+    let rules = Rules::for_path(conn, Path::new(path))?;
+    let mut seen = HashSet::new();
+    for entry in rules.walk(Path::new(path)) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Unreadable entries are skipped, but not an unreadable directory
+            Err(e) if e.depth().is_some_and(|d| d > 0) => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read directory {path}")),
+        };
+        if entry.depth() == 0 {
             continue;
         }
-        if p.is_dir() {
-            let name = entry.file_name();
-            let name = name.to_str().unwrap_or_else(|| "");
-            if name == ".git" || name == "target" {
-                continue;
-            }
-            conn.execute(
-                "INSERT OR IGNORE INTO dir_queue (path) VALUES (?);",
-                [entry_path],
-            )?;
+        let Some(entry_path) = entry.path().to_str() else {
+            continue;
+        };
+        let Ok(md) = entry.metadata() else {
+            continue;
+        };
+        if md.is_symlink() {
+            continue;
+        }
+        if md.is_dir() {
+            queue_dir(conn, entry_path)?;
+        } else if rules.too_large(entry.path(), md.len()) {
+            continue;
         } else {
-            conn.execute(
-                "INSERT OR IGNORE INTO file_queue (dir_id, path) VALUES (?1, ?2);",
-                params![id, entry_path],
-            )?;
+            queue_file(conn, Some(id), entry_path, &md)?;
         }
+        seen.insert(entry_path.to_string());
     }
-    conn.execute("UPDATE dir_queue SET status='done' WHERE id=?", [id])?;
-    Ok(true)
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT path FROM file_queue WHERE dir_id = ?1
+        UNION ALL
+        SELECT path FROM dir_queue
+        WHERE substr(path, 1, length(?2) + 1) = ?2 || '/'
+            AND instr(substr(path, length(?2) + 2), '/') = 0
+        "#,
+    )?;
+    let known: Vec<String> = stmt
+        .query_map(params![id, path], |r| r.get(0))?
+        .collect::<Result<_, _>>()?;
+    for known in known {
+        // Vanished paths are left to reconcile, which runs once moves have
+        // been matched up
+        if seen.contains(&known) || !Path::new(&known).exists() || contains_root(conn, &known)? {
+            continue;
+        }
+        let tx = conn.unchecked_transaction()?;
+        purge_path(&tx, &known)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Whether `path` is a root or has a root below it, whose files are indexed
+/// whatever the rules of the roots above it.
+fn contains_root(conn: &Connection, path: &str) -> anyhow::Result<bool> {
+    Ok(conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM roots WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '/')",
+        [path],
+        |r| r.get(0),
+    )?)
+}
+
+/// A worker's claim on a queue row. Other processes take the row over once the
+/// claim is older than the lease timeout, so long work renews it, see
+/// [`renew`]. Work on the row is only recorded while the claim holds: it is
+/// lost when the row is queued again or taken over.
+struct Lease {
+    table: &'static str,
+    id: i64,
+    /// Tells this claim apart from later ones on the row.
+    lease_id: i64,
+    /// Unix time at which the claim was taken or last renewed.
+    leased_at: Cell<i64>,
+}
+
+/// Matches the row of `lease` while its claim holds, given `?1` is its id and
+/// `?2` its lease id.
+const HELD: &str = "id = ?1 AND lease_id = ?2 AND status = 'scanning'";
+
+/// Claims up to `limit` rows of `table` that are ready to be worked on, along
+/// with their paths.
+fn claim(
+    conn: &Connection,
+    table: &'static str,
+    limit: i64,
+) -> anyhow::Result<Vec<(Lease, String)>> {
+    // Claiming in a single statement keeps two workers from taking the same rows
+    let claimed = conn
+        .prepare(&format!(
+            r#"
+            UPDATE {table} SET status = 'scanning', leased_at = unixepoch(), lease_id = random()
+            WHERE id IN (
+                SELECT id FROM {table}
+                WHERE status = 'pending'
+                    AND (next_attempt_at IS NULL OR next_attempt_at <= unixepoch())
+                LIMIT ?
+            )
+            RETURNING id, lease_id, leased_at, path
+            "#
+        ))?
+        .query_map([limit], |r| {
+            let lease = Lease {
+                table,
+                id: r.get(0)?,
+                lease_id: r.get(1)?,
+                leased_at: Cell::new(r.get(2)?),
+            };
+            Ok((lease, r.get(3)?))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(claimed)
 }
 
-fn scan_1_file(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    backend: &LlamaBackend,
-    model: &LlamaModel,
-) -> anyhow::Result<bool> {
-    let Some((id, path)): Option<(i64, String)> = conn
-        .query_one(
-            "SELECT id, path FROM file_queue WHERE status = 'pending' LIMIT 1",
-            [],
-            |r| Ok((r.get(0)?, r.get(1)?)),
+/// Renews `lease` once a quarter of the lease timeout has passed since it was
+/// last renewed. Fails if the claim was lost, which stops the work on the row.
+fn renew(conn: &Connection, lease: &Lease) -> anyhow::Result<()> {
+    let since_epoch = UNIX_EPOCH.elapsed().unwrap_or_default().as_secs() as i64;
+    if since_epoch - lease.leased_at.get() < config::get().indexing.lease_timeout() as i64 / 4 {
+        return Ok(());
+    }
+    let leased_at = conn
+        .query_row(
+            &format!(
+                "UPDATE {} SET leased_at = unixepoch() WHERE {HELD} RETURNING leased_at",
+                lease.table
+            ),
+            params![lease.id, lease.lease_id],
+            |r| r.get(0),
         )
-        .optional()?
-    else {
-        return Ok(false);
+        .optional()?;
+    let Some(leased_at) = leased_at else {
+        anyhow::bail!("lost the claim on {} row {}", lease.table, lease.id);
     };
+    lease.leased_at.set(leased_at);
+    Ok(())
+}
+
+/// Marks a claimed queue row done. Fails if the claim was lost, so that the
+/// caller's transaction is rolled back and the row is worked on again.
+fn succeed(conn: &Connection, lease: &Lease) -> anyhow::Result<()> {
+    let updated = conn.execute(
+        &format!(
+            "UPDATE {}
+            SET status = 'done', leased_at = NULL, attempts = 0, next_attempt_at = NULL, error = NULL
+            WHERE {HELD}",
+            lease.table
+        ),
+        params![lease.id, lease.lease_id],
+    )?;
+    anyhow::ensure!(
+        updated == 1,
+        "lost the claim on {} row {}",
+        lease.table,
+        lease.id
+    );
+    Ok(())
+}
+
+/// Records a failed attempt at a claimed queue row. The row is retried with
+/// exponential backoff until it runs out of attempts, and then left in the
+/// `error` state. Rows whose claim was lost are left alone.
+fn fail(conn: &Connection, lease: &Lease, error: &str) -> anyhow::Result<()> {
+    conn.execute(
+        &format!(
+            "UPDATE {} SET
+                status = CASE WHEN attempts + 1 >= ?4 THEN 'error' ELSE 'pending' END,
+                leased_at = NULL,
+                attempts = attempts + 1,
+                next_attempt_at = unixepoch() + (?5 << MIN(attempts, 16)),
+                error = ?3,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE {HELD}",
+            lease.table
+        ),
+        params![
+            lease.id,
+            lease.lease_id,
+            error,
+            config::get().indexing.max_attempts(),
+            RETRY_DELAY_SECS
+        ],
+    )?;
+    Ok(())
+}
+
+pub(crate) fn is_skipped_dir(name: &str) -> bool {
+    name == ".git" || name == "target"
+}
+
+/// Queues the directory at `path` for a crawl, unless one is already queued.
+pub(crate) fn queue_dir(conn: &Connection, path: &str) -> anyhow::Result<()> {
+    conn.execute(
+        r#"
+        INSERT INTO dir_queue (path) VALUES (?)
+        ON CONFLICT(path) DO UPDATE SET status = 'pending', updated_at = CURRENT_TIMESTAMP
+        WHERE dir_queue.status = 'done';
+        "#,
+        [path],
+    )?;
+    Ok(())
+}
+
+/// Queues the file at `path` for indexing. A file whose size or mtime changed
+/// since it was last seen is queued again; prepare_file then compares content
+/// hashes. A file changed while it is indexed loses its claim, see
+/// [`succeed`].
+pub(crate) fn queue_file(
+    conn: &Connection,
+    dir_id: Option<i64>,
+    path: &str,
+    md: &std::fs::Metadata,
+) -> anyhow::Result<()> {
+    conn.execute(
+        r#"
+        INSERT INTO file_queue (dir_id, path, mtime, size) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT(path) DO UPDATE SET
+            status = 'pending',
+            leased_at = NULL,
+            mtime = excluded.mtime,
+            size = excluded.size,
+            attempts = 0,
+            next_attempt_at = NULL,
+            updated_at = CURRENT_TIMESTAMP
+        WHERE file_queue.mtime IS NOT excluded.mtime OR file_queue.size IS NOT excluded.size;
+        "#,
+        params![dir_id, path, mtime(md), md.len() as i64],
+    )?;
+    Ok(())
+}
+
+fn mtime(md: &std::fs::Metadata) -> Option<i64> {
+    let modified = md.modified().ok()?;
+    let since_epoch = modified.duration_since(UNIX_EPOCH).ok()?;
+    Some(since_epoch.as_nanos() as i64)
+}
+
+/// Files claimed and embedded together, so that small files share batches.
+const FILES_PER_BATCH: i64 = 16;
+
+/// A claimed file, read and chunked, waiting for its embeddings.
+struct PreparedFile<'a> {
+    lease: &'a Lease,
+    path: String,
+    collection: Collection,
+    /// Embedding model of the collection.
+    model: &'static LlamaModel,
+    hash: String,
+    encoding: Option<&'static str>,
+    /// Chunks by the path they are indexed under: the file's own path, or the
+    /// virtual paths of the members of an archive.
+    documents: Vec<(String, TokenizedChunks)>,
+    /// Hashes of the members of an archive in `documents`, by virtual path.
+    member_hashes: Vec<(String, String)>,
+    /// Virtual paths of the members of an archive that are unchanged since it
+    /// was last indexed, and keep their chunks.
+    kept: Vec<String>,
+}
+
+impl PreparedFile<'_> {
+    fn chunks(&self) -> impl Iterator<Item = &(Chunk, Vec<LlamaToken>)> {
+        self.documents.iter().flat_map(|(_, chunks)| chunks)
+    }
+}
+
+fn scan_files(conn: &PooledConnection<SqliteConnectionManager>) -> anyhow::Result<bool> {
+    let claimed = claim(conn, "file_queue", FILES_PER_BATCH)?;
+    if claimed.is_empty() {
+        return Ok(false);
+    }
+
+    // The files of each collection are embedded together, with its model
+    let mut prepared: BTreeMap<i64, Vec<PreparedFile>> = BTreeMap::new();
+    for (lease, path) in &claimed {
+        match prepare_file(conn, lease, path.clone()) {
+            Ok(Some(file)) => prepared.entry(file.collection.id).or_default().push(file),
+            Ok(None) => {}
+            Err(e) => fail(conn, lease, &format!("{e:#}"))?,
+        }
+    }
 
-    // Update status to scanning
-    conn.execute("UPDATE file_queue SET status='scanning' WHERE id=?", [id])?;
-
-    // Check if it's a text file
-    if !is_text_file(&path) {
-        conn.execute("UPDATE file_queue SET status='done' WHERE id=?", [id])?;
-        return Ok(true);
-    }
-
-    // Read and process the file
-    match std::fs::read_to_string(&path) {
-        Ok(content) => {
-            let embedding_chunks = tokenize_document_chunks(&content, backend, model)?;
-            for (chunk_index, (chunk, embedding)) in embedding_chunks.into_iter().enumerate() {
-                conn.execute(
-                    "INSERT INTO documents (file_path, chunk_index, content) VALUES (?, ?, ?)",
-                    params![&path, chunk_index as i64, chunk],
-                )?;
-                conn.execute(
-                    "INSERT INTO embeddings (file_path, chunk_index, content, embedding) VALUES (?, ?, ?, ?)",
-                    params![&path, chunk_index as i64, chunk, embedding.as_bytes()],
-                )?;
+    for files in prepared.into_values() {
+        let embeddings: Vec<anyhow::Result<Vec<Vec<f32>>>> = match embed_files(conn, &files) {
+            Ok(embeddings) => embeddings.into_iter().map(Ok).collect(),
+            Err(e) if files.len() == 1 => vec![Err(e)],
+            // Find the files at fault by embedding them one at a time, so that
+            // the others do not use up their attempts
+            Err(_) => files
+                .iter()
+                .map(|f| embed_files(conn, std::slice::from_ref(f)).map(|mut e| e.remove(0)))
+                .collect(),
+        };
+        for (file, embeddings) in files.iter().zip(embeddings) {
+            if let Err(e) = embeddings.and_then(|e| store_file(conn, file, e)) {
+                fail(conn, file.lease, &format!("{e:#}"))?;
             }
-            conn.execute("UPDATE file_queue SET status='done' WHERE id=?", [id])?;
         }
-        Err(e) => {
-            // Mark as error if reading fails
-            conn.execute(
-                "UPDATE file_queue SET status='error', error=? WHERE id=?",
-                params![format!("Failed to read file: {}", e), id],
+    }
+    Ok(true)
+}
+
+/// Chunks embedded in one go by [`embed_files`], which renews the leases of
+/// their files in between.
+const EMBED_BATCH: usize = 256;
+
+/// Embeds the chunks of `files`, which are in the same collection, together.
+/// Returns the embeddings of each file.
+fn embed_files(conn: &Connection, files: &[PreparedFile]) -> anyhow::Result<Vec<Vec<Vec<f32>>>> {
+    let chunks: Vec<&[LlamaToken]> = files
+        .iter()
+        .flat_map(|f| f.chunks().map(|(_, tokens)| tokens.as_slice()))
+        .collect();
+    let mut embeddings = Vec::with_capacity(chunks.len());
+    for batch in chunks.chunks(EMBED_BATCH) {
+        // A large mailbox can take longer to embed than the lease timeout
+        for file in files {
+            renew(conn, file.lease)?;
+        }
+        embeddings.extend(embed_collection_chunks(conn, &files[0], batch)?);
+    }
+    let mut embeddings = embeddings.into_iter();
+    Ok(files
+        .iter()
+        .map(|f| embeddings.by_ref().take(f.chunks().count()).collect())
+        .collect())
+}
+
+/// Embeds the chunks of files in the collection of `file` with its model.
+fn embed_collection_chunks(
+    conn: &Connection,
+    file: &PreparedFile,
+    chunks: &[&[LlamaToken]],
+) -> anyhow::Result<Vec<Vec<f32>>> {
+    // The collection may have been created since the scanner started
+    if db::target_index(conn, file.collection.id)?.is_none() {
+        sync_collection(conn, &file.collection)?;
+    }
+    embed_chunks(chunks, file.model)
+}
+
+/// Reads and chunks the file at `path` with the settings of its collection.
+/// Returns `None` if the file needs no embedding, in which case its queue row
+/// has already been marked done.
+fn prepare_file<'a>(
+    conn: &Connection,
+    lease: &'a Lease,
+    path: String,
+) -> anyhow::Result<Option<PreparedFile<'a>>> {
+    let id = lease.id;
+    let old_hash: Option<String> =
+        conn.query_row("SELECT hash FROM file_queue WHERE id = ?", [id], |r| {
+            r.get(0)
+        })?;
+    let collection = db::collection_of(conn, &path)?;
+    let settings = config::get().collection(&collection.name);
+    let model = get_model(settings.embedding)?;
+    // Read and process the file, starting with enough to tell its type
+    let mut file = File::open(&path).with_context(|| "Failed to read file")?;
+    let mut bytes = vec![];
+    file.by_ref()
+        .take(extract::SAMPLE_LEN)
+        .read_to_end(&mut bytes)
+        .with_context(|| "Failed to read file")?;
+    let file_type = extract::detect(&path, &bytes);
+    conn.execute(
+        "UPDATE file_queue SET mime=? WHERE id=?",
+        params![file_type.mime, id],
+    )?;
+    if file_type.kind == extract::Kind::Unsupported {
+        // It may have held text when it was last indexed
+        let tx = conn.unchecked_transaction()?;
+        delete_chunks(&tx, &path)?;
+        tx.execute("UPDATE file_queue SET hash=NULL WHERE id=?", [id])?;
+        succeed(&tx, lease)?;
+        tx.commit()?;
+        return Ok(None);
+    }
+    file.read_to_end(&mut bytes)
+        .with_context(|| "Failed to read file")?;
+    let hash = blake3::hash(&bytes).to_hex().to_string();
+    if old_hash.as_deref() == Some(hash.as_str()) {
+        // Touched but unchanged, the existing chunks are still valid
+        succeed(conn, lease)?;
+        return Ok(None);
+    }
+    if let Some(old_path) = find_moved_from(conn, &path, &hash, &collection)? {
+        // Renamed while we were not watching, reuse the old chunks
+        let tx = conn.unchecked_transaction()?;
+        move_chunks(&tx, &old_path, &path)?;
+        tx.execute("DELETE FROM file_queue WHERE path=?", [&old_path])?;
+        tx.execute("UPDATE file_queue SET hash=? WHERE id=?", params![hash, id])?;
+        succeed(&tx, lease)?;
+        tx.commit()?;
+        return Ok(None);
+    }
+    let mut file = PreparedFile {
+        lease,
+        path,
+        collection: collection.clone(),
+        model,
+        hash,
+        encoding: None,
+        documents: vec![],
+        member_hashes: vec![],
+        kept: vec![],
+    };
+    if file_type.kind.is_archive() {
+        archive::for_each_member(
+            &file.path,
+            file_type.kind,
+            &bytes,
+            &mut |path, member_type, bytes| {
+                // A large mailbox can take longer to go through than the lease
+                // timeout
+                renew(conn, file.lease)?;
+                // Members unchanged since the archive was last indexed keep
+                // their chunks, so that a mailbox that grew only has its new
+                // messages embedded
+                let hash = blake3::hash(&bytes).to_hex().to_string();
+                if db::member_hash(conn, &path)?.as_deref() == Some(hash.as_str()) {
+                    file.kept.push(path);
+                    return Ok(());
+                }
+                // One unreadable member does not keep the rest of the archive out
+                // of the index
+                match extract::extract(&path, member_type.kind, bytes) {
+                    Ok(document) => {
+                        let chunks = tokenize_document_chunks(&document, model, &settings)?;
+                        file.documents.push((path.clone(), chunks));
+                        file.member_hashes.push((path, hash));
+                    }
+                    Err(e) => eprintln!("Unable to index {path}: {e:#}"),
+                }
+                Ok(())
+            },
+        )?;
+    } else {
+        let document = extract::extract(&file.path, file_type.kind, bytes)?;
+        file.encoding = document.encoding;
+        let chunks = tokenize_document_chunks(&document, model, &settings)?;
+        file.documents.push((file.path.clone(), chunks));
+    }
+    Ok(Some(file))
+}
+
+/// Replaces the indexed chunks of `file`, but those of its unchanged members,
+/// marking its queue row done.
+fn store_file(
+    conn: &Connection,
+    file: &PreparedFile,
+    embeddings: Vec<Vec<f32>>,
+) -> anyhow::Result<()> {
+    // Swap the old chunks for the new ones in one step, so searches
+    // never see a half-indexed file.
+    let tx = conn.unchecked_transaction()?;
+    let Some(index) = db::target_index(&tx, file.collection.id)? else {
+        anyhow::bail!("no embedding index for collection {}", file.collection.name);
+    };
+    db::clear_chunks(&tx, &file.path, file.collection.id, &file.kept)?;
+    let mut embeddings = embeddings.into_iter();
+    for (path, chunks) in &file.documents {
+        for (chunk_index, ((chunk, _), embedding)) in
+            chunks.iter().zip(embeddings.by_ref()).enumerate()
+        {
+            let metadata = if chunk.metadata.is_empty() {
+                None
+            } else {
+                Some(serde_json::to_string(&chunk.metadata)?)
+            };
+            tx.execute(
+                r#"
+                INSERT INTO documents
+                    (file_path, chunk_index, content, byte_start, byte_end, line_start, line_end, token_count, metadata, collection_id)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                "#,
+                params![
+                    path,
+                    chunk_index as i64,
+                    chunk.text,
+                    chunk.byte_start as i64,
+                    chunk.byte_end as i64,
+                    chunk.line_start as i64,
+                    chunk.line_end as i64,
+                    chunk.token_count as i64,
+                    metadata,
+                    file.collection.id,
+                ],
+            )?;
+            // Embeddings share the rowid of their chunk
+            let rowid = tx.last_insert_rowid();
+            tx.execute(
+                &format!(
+                    r#"
+                    INSERT INTO {}
+                        (rowid, file_path, chunk_index, content, byte_start, byte_end, line_start, line_end, token_count, metadata, embedding)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                    "#,
+                    index.name
+                ),
+                params![
+                    rowid,
+                    path,
+                    chunk_index as i64,
+                    chunk.text,
+                    chunk.byte_start as i64,
+                    chunk.byte_end as i64,
+                    chunk.line_start as i64,
+                    chunk.line_end as i64,
+                    chunk.token_count as i64,
+                    metadata,
+                    embedding.as_bytes(),
+                ],
             )?;
         }
     }
-    Ok(true)
+    for (path, hash) in &file.member_hashes {
+        tx.execute(
+            "INSERT INTO member_hashes (file_path, hash) VALUES (?, ?)",
+            params![path, hash],
+        )?;
+    }
+    tx.execute(
+        "UPDATE file_queue SET hash=?, encoding=? WHERE id=?",
+        params![file.hash, file.encoding, file.lease.id],
+    )?;
+    succeed(&tx, file.lease)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Chunks embedded together by [`rebuild_index`].
+const REBUILD_BATCH: i64 = 64;
+
+/// Embeds the indexed chunks of every collection whose embedding model
+/// changed with the configured model, into the index being built for it, then
+/// has searches switch over to it. Chunks of files indexed meanwhile are
+/// already embedded into it by the workers.
+fn rebuild_embeddings(pool: &Pool<SqliteConnectionManager>) -> anyhow::Result<()> {
+    let conn = pool.get()?;
+    for collection in db::list_collections(&conn)? {
+        let Some(index) = db::building_index(&conn, collection.id)? else {
+            continue;
+        };
+        rebuild_index(&conn, &collection, &index)?;
+    }
+    Ok(())
 }
 
-fn is_text_file(path: &str) -> bool {
-    let text_extensions = [
-        "txt",
-        "rs",
-        "js",
-        "ts",
-        "py",
-        "java",
-        "c",
-        "cpp",
-        "h",
-        "hpp",
-        "html",
-        "css",
-        "md",
-        "json",
-        "yaml",
-        "yml",
-        "toml",
-        "xml",
-        "sh",
-        "bash",
-        "zsh",
-        "fish",
-        "sql",
-        "go",
-        "rb",
-        "php",
-        "pl",
-        "pm",
-        "lua",
-        "r",
-        "scala",
-        "kt",
-        "swift",
-        "dart",
-        "hs",
-        "ml",
-        "fs",
-        "fsx",
-        "vb",
-        "cs",
-        "fs",
-        "elm",
-        "clj",
-        "cljs",
-        "ex",
-        "exs",
-        "nim",
-        "cr",
-        "v",
-        "zig",
-        "jl",
-        "scm",
-        "ss",
-        "rkt",
-        "hy",
-        "coffee",
-        "litcoffee",
-        "ls",
-        "moon",
-        "iced",
-        "pl",
-        "pm",
-        "t",
-        "pod",
-        "awk",
-        "sed",
-        "makefile",
-        "dockerfile",
-        "gitignore",
-        "readme",
-        "license",
-        "changelog",
-        "authors",
-        "contributors",
-        "news",
-        "history",
-        "todo",
-    ];
-
-    if let Some(ext) = std::path::Path::new(path).extension() {
-        if let Some(ext_str) = ext.to_str() {
-            return text_extensions.contains(&ext_str.to_lowercase().as_str());
-        }
-    }
-
-    // For files without extensions, try to read first few bytes to check if text
-    if let Ok(bytes) = std::fs::read(&path) {
-        if bytes.len() > 0 {
-            // Check if first 1024 bytes are valid UTF-8
-            let check_len = std::cmp::min(1024, bytes.len());
-            return std::str::from_utf8(&bytes[..check_len]).is_ok();
-        }
-    }
-
-    false
+fn rebuild_index(
+    conn: &Connection,
+    collection: &Collection,
+    index: &EmbeddingIndex,
+) -> anyhow::Result<()> {
+    let settings = config::get().collection(&collection.name);
+    let model_path = index.model.as_deref().unwrap_or_default();
+    let model = get_model(Path::new(model_path))?;
+    eprintln!(
+        "Rebuilding embeddings of collection {} with {model_path}",
+        collection.name
+    );
+    let mut last = 0;
+    loop {
+        let batch: Vec<(i64, String)> = conn
+            .prepare(&format!(
+                r#"
+                SELECT d.rowid, d.content FROM documents d
+                WHERE d.collection_id = ?1 AND d.rowid > ?2
+                    AND NOT EXISTS (SELECT 1 FROM {} e WHERE e.rowid = d.rowid)
+                ORDER BY d.rowid
+                LIMIT ?3
+                "#,
+                index.name
+            ))?
+            .query_map(params![collection.id, last, REBUILD_BATCH], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        let Some(&(rowid, _)) = batch.last() else {
+            break;
+        };
+        last = rowid;
+        // A chunk the model cannot take is left out of the index rather than
+        // holding up the whole rebuild
+        let skip = |rowid: i64, e: anyhow::Error| {
+            eprintln!(
+                "Not embedding chunk {rowid} of collection {}: {e:#}",
+                collection.name
+            );
+        };
+        let mut chunks = vec![];
+        for (rowid, content) in batch {
+            match tokenize_chunk(&content, model, &settings) {
+                Ok(tokens) => chunks.push((rowid, tokens)),
+                Err(e) => skip(rowid, e),
+            }
+        }
+        let tokens: Vec<&[LlamaToken]> = chunks.iter().map(|(_, t)| t.as_slice()).collect();
+        let embeddings: Vec<(i64, Vec<f32>)> = match embed_chunks(&tokens, model) {
+            Ok(embeddings) => chunks
+                .iter()
+                .map(|(rowid, _)| *rowid)
+                .zip(embeddings)
+                .collect(),
+            // Find the chunks at fault by embedding them one at a time
+            Err(_) => chunks
+                .iter()
+                .filter_map(
+                    |(rowid, tokens)| match embed_chunks(&[tokens.as_slice()], model) {
+                        Ok(mut embeddings) => Some((*rowid, embeddings.remove(0))),
+                        Err(e) => {
+                            skip(*rowid, e);
+                            None
+                        }
+                    },
+                )
+                .collect(),
+        };
+        store_embeddings(conn, index, embeddings)?;
+    }
+    db::activate_embedding_index(conn, index)?;
+    eprintln!(
+        "Embeddings of collection {} rebuilt with {model_path}",
+        collection.name
+    );
+    Ok(())
+}
+
+/// Stores `embeddings`, by the rowid of their chunk in `documents`. Chunks
+/// deleted or re-indexed since they were read are skipped.
+fn store_embeddings(
+    conn: &Connection,
+    index: &EmbeddingIndex,
+    embeddings: Vec<(i64, Vec<f32>)>,
+) -> anyhow::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for (rowid, embedding) in embeddings {
+        tx.execute(
+            &format!(
+                r#"
+                INSERT INTO {0}
+                    (rowid, file_path, chunk_index, content, byte_start, byte_end, line_start, line_end, token_count, metadata, embedding)
+                SELECT rowid, file_path, chunk_index, content, byte_start, byte_end, line_start, line_end, token_count, metadata, ?2
+                FROM documents
+                WHERE rowid = ?1 AND NOT EXISTS (SELECT 1 FROM {0} WHERE rowid = ?1)
+                "#,
+                index.name
+            ),
+            params![rowid, embedding.as_bytes()],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Finds an indexed file of `collection` with the given content hash that no
+/// longer exists, i.e. the file that `path` was moved from. Files moved from
+/// another collection were embedded with another model and are indexed again.
+fn find_moved_from(
+    conn: &Connection,
+    path: &str,
+    hash: &str,
+    collection: &Collection,
+) -> anyhow::Result<Option<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT path FROM file_queue WHERE hash = ?1 AND status = 'done' AND path != ?2",
+    )?;
+    let mut rows = stmt.query(params![hash, path])?;
+    while let Some(row) = rows.next()? {
+        let candidate: String = row.get(0)?;
+        if !Path::new(&candidate).exists() && db::collection_of(conn, &candidate)? == *collection {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+/// Drops queue entries and chunks of files and directories that no longer
+/// exist.
+pub(crate) fn reconcile(conn: &Connection) -> anyhow::Result<()> {
+    let vanished_dirs = vanished(conn, "SELECT path FROM dir_queue")?;
+    let vanished_files = vanished(conn, "SELECT path FROM file_queue")?;
+    let tx = conn.unchecked_transaction()?;
+    for path in vanished_dirs.iter().chain(&vanished_files) {
+        purge_path(&tx, path)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn vanished(conn: &Connection, query: &str) -> anyhow::Result<Vec<String>> {
+    let mut stmt = conn.prepare(query)?;
+    let mut rows = stmt.query([])?;
+    let mut paths = vec![];
+    while let Some(row) = rows.next()? {
+        let path: String = row.get(0)?;
+        if !Path::new(&path).exists() {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Queue {
+        conn: Connection,
+        dir: std::path::PathBuf,
+    }
+
+    impl Queue {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("lmtools-workers-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            config::set_for_tests(config::Config::default());
+            let conn = Connection::open_in_memory().unwrap();
+            db::migrate(&conn).unwrap();
+            Queue { conn, dir }
+        }
+
+        /// Writes `content` to a file and queues it.
+        fn write(&self, content: &str) {
+            let path = self.dir.join("a.txt");
+            std::fs::write(&path, content).unwrap();
+            let md = std::fs::metadata(&path).unwrap();
+            queue_file(&self.conn, None, path.to_str().unwrap(), &md).unwrap();
+        }
+
+        fn claim(&self) -> Lease {
+            claim(&self.conn, "file_queue", 1).unwrap().remove(0).0
+        }
+
+        fn status(&self) -> String {
+            self.conn
+                .query_row("SELECT status FROM file_queue", [], |r| r.get(0))
+                .unwrap()
+        }
+    }
+
+    impl Drop for Queue {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn files_changed_while_indexed_are_indexed_again() {
+        let queue = Queue::new("changed");
+        queue.write("a");
+        let lease = queue.claim();
+        queue.write("edited");
+        assert!(succeed(&queue.conn, &lease).is_err());
+        assert_eq!(queue.status(), "pending");
+
+        let lease = queue.claim();
+        succeed(&queue.conn, &lease).unwrap();
+        assert_eq!(queue.status(), "done");
+    }
+
+    #[test]
+    fn taken_over_claims_are_not_recorded() {
+        let queue = Queue::new("taken-over");
+        queue.write("a");
+        let stale = queue.claim();
+        // Renewing a fresh lease is a no-op
+        renew(&queue.conn, &stale).unwrap();
+        queue
+            .conn
+            .execute("UPDATE file_queue SET leased_at = leased_at - 1000", [])
+            .unwrap();
+        stale.leased_at.set(stale.leased_at.get() - 1000);
+        db::reclaim_stale_leases(&queue.conn, 600).unwrap();
+        let lease = queue.claim();
+
+        assert!(renew(&queue.conn, &stale).is_err());
+        assert!(succeed(&queue.conn, &stale).is_err());
+        fail(&queue.conn, &stale, "stale").unwrap();
+        assert_eq!(queue.status(), "scanning");
+        succeed(&queue.conn, &lease).unwrap();
+        assert_eq!(queue.status(), "done");
+    }
+
+    #[test]
+    fn renews_leases() {
+        let queue = Queue::new("renewed");
+        queue.write("a");
+        let lease = queue.claim();
+        lease.leased_at.set(lease.leased_at.get() - 1000);
+        renew(&queue.conn, &lease).unwrap();
+        let leased_at: i64 = queue
+            .conn
+            .query_row("SELECT leased_at FROM file_queue", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(leased_at, lease.leased_at.get());
+        db::reclaim_stale_leases(&queue.conn, 600).unwrap();
+        assert_eq!(queue.status(), "scanning");
+    }
 }